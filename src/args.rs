@@ -3,6 +3,13 @@ pub fn get_args() -> clap::App<'static, 'static> {
         .version("0.0.1")
         .author("Will C. <cleghornw@gmail.com>")
         .about("Provides a WebSocket Interface for BTCPay Invoice Webhooks")
+        .arg(
+            clap::Arg::with_name("listen")
+                .long("listen")
+                .value_name("ADDRESS")
+                .help("Address the HTTP Server Binds To (default 0.0.0.0:5000)")
+                .takes_value(true),
+        )
         .arg(
             clap::Arg::with_name("redis-host")
                 .short("h")
@@ -27,6 +34,53 @@ pub fn get_args() -> clap::App<'static, 'static> {
                 .help("BTCPay HMAC to Verify Incoming Updates")
                 .takes_value(true),
         )
+        .arg(
+            clap::Arg::with_name("store")
+                .long("store")
+                .value_name("STORE_ID=SECRET")
+                .help("Adds a Store Webhook Secret, Also Takes STORE_ID:LABEL=SECRET EXPIRES")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            clap::Arg::with_name("secrets-file")
+                .long("secrets-file")
+                .value_name("PATH")
+                .help("File of --store Values, One per Line, Reloaded on SIGHUP")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("btcpay-url")
+                .long("btcpay-url")
+                .value_name("URL")
+                .help("BTCPay Server to Check Legacy IPNs Against, Enables /btcpay/ipn")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("btcpay-api-key")
+                .long("btcpay-api-key")
+                .value_name("API_KEY")
+                .help("Greenfield API Key, Enables Looking Up Invoices Missing Webhooks")
+                .takes_value(true)
+                .requires("btcpay-url"),
+        )
+        .arg(
+            clap::Arg::with_name("btcpay-store")
+                .long("btcpay-store")
+                .value_name("STORE_ID")
+                .help("BTCPay Store ID Behind --hmac, for Greenfield Lookups")
+                .takes_value(true)
+                .requires("btcpay-api-key"),
+        )
+        .arg(
+            clap::Arg::with_name("reconcile-interval")
+                .long("reconcile-interval")
+                .value_name("SECONDS")
+                .help("How Often Pending Invoices Are Re-Checked With BTCPay, 0 Disables (default 60)")
+                .takes_value(true)
+                .requires("btcpay-api-key"),
+        )
         .arg(
             clap::Arg::with_name("redis-password")
                 .short("a")
@@ -35,4 +89,91 @@ pub fn get_args() -> clap::App<'static, 'static> {
                 .help("Password for Redis")
                 .takes_value(true),
         )
+        .arg(
+            clap::Arg::with_name("redis-url")
+                .long("redis-url")
+                .value_name("REDIS_URL")
+                .help("Redis Connection URL, Overrides Host, Port and Password")
+                .takes_value(true)
+                .conflicts_with_all(&["redis-port", "redis-password"]),
+        )
+        .arg(
+            clap::Arg::with_name("redis-pool-size")
+                .long("redis-pool-size")
+                .value_name("CONNECTIONS")
+                .help("Number of Pooled Redis Connections (default 4)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("redis-timeout")
+                .long("redis-timeout")
+                .value_name("MILLISECONDS")
+                .help("Timeout for Redis Commands (default 2000)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("key-prefix")
+                .long("key-prefix")
+                .value_name("PREFIX")
+                .help("Prefix of Every Redis Key Written (default btcpayws)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("delivery-ttl")
+                .long("delivery-ttl")
+                .value_name("SECONDS")
+                .help("How Long Processed Webhook Deliveries Are Remembered (default 86400)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("terminal-ttl")
+                .long("terminal-ttl")
+                .value_name("SECONDS")
+                .help("How Long Finished Invoices Are Kept, 0 Keeps Them (default 604800)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("pending-ttl")
+                .long("pending-ttl")
+                .value_name("SECONDS")
+                .help("How Long Unfinished Invoices Are Kept, 0 Keeps Them (default 2592000)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("max-webhook-age")
+                .long("max-webhook-age")
+                .value_name("SECONDS")
+                .help("Refuses Webhooks Timestamped Further From Now, 0 Disables (default 3600)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("pending-timeout")
+                .long("pending-timeout")
+                .value_name("SECONDS")
+                .help("How Long Sockets Wait for an Unknown Invoice to Appear (default 900)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("ping-interval")
+                .long("ping-interval")
+                .value_name("SECONDS")
+                .help("How Often Sockets Are Pinged to Check on Them, 0 Disables (default 30)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("max-socket-lifetime")
+                .long("max-socket-lifetime")
+                .value_name("SECONDS")
+                .help("Longest a Socket Stays Open, 0 Disables (default 86400)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("fan-out")
+                .long("fan-out")
+                .help("Shares Invoice Updates With Other Instances Through Redis Pub/Sub"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("migrate")
+                .about("Moves Invoices Kept by Older Versions Under --key-prefix, Then Exits"),
+        )
 }
@@ -1,19 +1,18 @@
-use async_trait::async_trait;
-use redis::Commands;
-use serde::Deserialize;
-use std::{error::Error, fmt};
 use tide::convert::json;
 extern crate log;
-use super::invoice::{InvoiceCommands, InvoiceError};
-use super::state::State;
+use super::client::ClientError;
+use super::invoice::{InvoiceCommands, InvoiceError, InvoiceKey, InvoiceUpdate};
+use super::state::{ReplayError, State};
+use super::store::DEFAULT_STORE;
+use super::webhook::{IpnPayload, Webhook};
 
 pub async fn handle_btcpay<T: InvoiceCommands + std::clone::Clone>(
     mut req: tide::Request<State<T>>,
 ) -> tide::Result<tide::Response> {
     log::trace!("{}", "Handling invoice update");
 
-    let body_str: String = match req.body_string().await {
-        Ok(body) => body.clone(),
+    let body: Vec<u8> = match req.body_bytes().await {
+        Ok(body) => body,
         Err(..) => {
             log::trace!("request missing body");
             return Ok(tide::Response::builder(400)
@@ -23,9 +22,9 @@ pub async fn handle_btcpay<T: InvoiceCommands + std::clone::Clone>(
     };
 
     // Get invoice update
-    let update: InvoiceUpdate = match serde_json::from_str::<InvoiceUpdate>(&body_str) {
-        Ok(update) => update,
-        Err(e) => {
+    let webhook: Webhook = match serde_json::from_slice::<Webhook>(&body) {
+        Ok(webhook) => webhook,
+        Err(_) => {
             log::trace!("request contains invalid/bad body");
             return Ok(tide::Response::builder(400)
                 .body(json!({"message": "invalid body"}))
@@ -33,135 +32,298 @@ pub async fn handle_btcpay<T: InvoiceCommands + std::clone::Clone>(
         }
     };
 
-    // Fetch btcpay sig
-    let btcpay_sig = match req.header("BTCPAY-SIG") {
-        Some(sig) => sig,
+    let status = match webhook.event.status() {
+        Some(status) => status,
         None => {
+            log::debug!("unsupported event type on invoice {}", webhook.invoice_id);
             return Ok(tide::Response::builder(400)
-                .body(json!({"detail": "missing BTCPAY-SIG header"}))
-                .build())
+                .body(json!({"message": "unsupported event type"}))
+                .build());
         }
     };
 
-    // Verify signature
-    let sig_string = btcpay_sig[0].to_string();
-    let sig_parts: Vec<&str> = sig_string.split('=').collect(); // Expects sha256=somekey
-                                                                // Assert format of signature header is something=something
-    if sig_parts.len() != 2 {
-        return Ok(tide::Response::builder(401)
-            .body(json!({"detail": "invalid BTCPAY-SIG"}))
-            .build());
-    }
+    // Pick the store whose secret signs this webhook
+    let store = match req
+        .state()
+        .select_store(req.param("store_id").ok(), webhook.store_id.as_deref())
+    {
+        Some(store) => store,
+        None => {
+            log::debug!("no store configured for invoice {}", webhook.invoice_id);
+            return Ok(tide::Response::builder(404)
+                .body(json!({"detail": "unknown store"}))
+                .build());
+        }
+    };
 
-    // Assert hash func is supported
-    if sig_parts[0] != "sha256" {
-        log::debug!("{}", sig_string);
-        return Ok(tide::Response::builder(401)
-            .body(json!({"detail": "invalid hmac operation"}))
+    // Verify signature over the body exactly as received
+    let signature = req.header("BTCPAY-SIG").map(|sig| sig.as_str());
+    if let Err(e) = req.state().verify_signature(&store, &body, signature) {
+        log::debug!("Refused webhook for invoice {}, {}", webhook.invoice_id, e);
+        return Ok(tide::Response::builder(e.status())
+            .body(json!({ "detail": e.detail() }))
             .build());
     }
 
-    log::trace!("{}", sig_parts[1].to_string());
+    let state = req.state();
 
-    if &req.state().verify_hmac(body_str, sig_parts[1].to_string()) == &false {
-        return Ok(tide::Response::builder(401)
-            .body(json!({"detail": "invalid hmac"}))
-            .build());
+    // Replay protection: a signed body is only good within the freshness
+    // window, and each delivery only once within it.
+    if let Err(e) = state.check_timestamp(webhook.timestamp) {
+        return Ok(replay_response(e, &webhook));
+    }
+    let mut claimed = None;
+    if let (Some(max_age), Some(delivery_id)) = (state.max_webhook_age, &webhook.delivery_id) {
+        // Kept for the window either side of now, the span check_timestamp accepts
+        match state.db.claim_delivery(delivery_id, max_age * 2).await {
+            Ok(true) => claimed = Some(delivery_id.clone()),
+            Ok(false) => return Ok(replay_response(ReplayError::AlreadySeen, &webhook)),
+            Err(_) => return Ok(tide::Response::builder(500).build()),
+        }
     }
 
-    {
-        let mut db = req.state().db.lock().await;
-        match db
-            .set_invoice_status(update.invoice_id.clone(), update.status.clone())
-            .await
-        {
-            Err(_) => Ok(tide::Response::builder(500).build()),
-            _ => Ok(tide::Response::builder(200)
-                .body(json!({"message": "update synced"}))
-                .build()),
+    // Redeliveries carry a fresh deliveryId, the event itself is identified
+    // by the delivery it was first sent as.
+    let delivery_id = webhook
+        .original_delivery_id
+        .clone()
+        .or_else(|| webhook.delivery_id.clone());
+    let update = InvoiceUpdate {
+        key: InvoiceKey::new(&store.id, &webhook.invoice_id),
+        status,
+        event: Some(webhook.event),
+        cursor: None,
+    };
+
+    // A redelivery of an event that has since been overtaken is
+    // acknowledged so BTCPay stops retrying it.
+    let response = apply_update(state, update, delivery_id, webhook.is_redelivery).await;
+
+    // Let BTCPay's retry of a delivery we failed on through
+    if response.status().is_server_error() {
+        if let Some(delivery_id) = &claimed {
+            if state.db.release_delivery(delivery_id).await.is_err() {
+                log::warn!("Failed to release delivery {}", delivery_id);
+            }
         }
     }
+
+    Ok(response)
 }
 
-#[derive(Debug, Deserialize)]
-struct InvoiceUpdate {
-    #[serde(rename = "type")]
-    status: String,
-    #[serde(rename = "invoiceId")]
-    invoice_id: String,
+/// Legacy Bitpay-style IPN. It carries no signature, so the body only names
+/// the invoice and its status is fetched back from BTCPay.
+pub async fn handle_ipn<T: InvoiceCommands + std::clone::Clone>(
+    mut req: tide::Request<State<T>>,
+) -> tide::Result<tide::Response> {
+    log::trace!("Handling IPN");
+
+    let payload: IpnPayload = match req.body_json().await {
+        Ok(payload) => payload,
+        Err(_) => {
+            log::trace!("request contains invalid/bad body");
+            return Ok(tide::Response::builder(400)
+                .body(json!({"message": "invalid body"}))
+                .build());
+        }
+    };
+    let state = req.state();
+
+    // Unsigned, so at least only configured stores get invoices written,
+    // `/btcpay/ipn` needing a default store
+    let route = req.param("store_id").ok();
+    let store = match state.select_store(route, None) {
+        Some(store) => store,
+        None => {
+            log::debug!("IPN for unknown store {}", route.unwrap_or(DEFAULT_STORE));
+            return Ok(tide::Response::builder(404)
+                .body(json!({"detail": "unknown store"}))
+                .build());
+        }
+    };
+
+    let client = match &state.btcpay {
+        Some(client) => client,
+        None => {
+            log::warn!("Received IPN but no BTCPay URL is configured");
+            return Ok(tide::Response::builder(404)
+                .body(json!({"detail": "ipn not configured"}))
+                .build());
+        }
+    };
+
+    let invoice_id = &payload.invoice().id;
+    let invoice = match client.get_legacy_invoice(invoice_id).await {
+        Ok(invoice) if &invoice.id == invoice_id => invoice,
+        Ok(_) | Err(ClientError::NotFound) => {
+            log::warn!("IPN for invoice {} unknown to BTCPay", invoice_id);
+            return Ok(tide::Response::builder(404)
+                .body(json!({"detail": "unknown invoice"}))
+                .build());
+        }
+        Err(_) => {
+            return Ok(tide::Response::builder(502)
+                .body(json!({"detail": "invoice lookup failed"}))
+                .build())
+        }
+    };
+
+    let status = match invoice.status() {
+        Some(status) => status,
+        None => {
+            log::debug!(
+                "unsupported status {} on invoice {}",
+                invoice.status,
+                invoice.id
+            );
+            return Ok(tide::Response::builder(400)
+                .body(json!({"message": "unsupported invoice status"}))
+                .build());
+        }
+    };
+
+    let update = InvoiceUpdate {
+        key: InvoiceKey::new(&store.id, &invoice.id),
+        status,
+        event: None,
+        cursor: None,
+    };
+
+    // The status comes from BTCPay itself, an IPN that lost a race with a
+    // later one still succeeded.
+    Ok(apply_update(state, update, None, true).await)
 }
 
-#[derive(Debug)]
-enum RedisError {
-    Connection,
+fn replay_response(error: ReplayError, webhook: &Webhook) -> tide::Response {
+    log::warn!(
+        "Refused webhook for invoice {} as a possible replay, {:?}",
+        webhook.invoice_id,
+        error
+    );
+    match error {
+        ReplayError::MissingTimestamp => tide::Response::builder(400)
+            .body(json!({"detail": "missing timestamp"}))
+            .build(),
+        ReplayError::Stale => tide::Response::builder(400)
+            .body(json!({"detail": "stale webhook"}))
+            .build(),
+        ReplayError::AlreadySeen => tide::Response::builder(409)
+            .body(json!({"detail": "delivery already seen"}))
+            .build(),
+    }
 }
 
-impl Error for RedisError {}
+/// Stores an update unless its delivery was already processed, then passes
+/// it on to subscribers. The delivery is only marked processed along with the
+/// write, so a failed attempt can still be retried. Updates the transition table rejects get a 409, or
+/// a 200 when `acknowledge_stale` is set. Payments to an invoice past taking
+/// them only go in its history.
+async fn apply_update<T: InvoiceCommands + std::clone::Clone>(
+    state: &State<T>,
+    mut update: InvoiceUpdate,
+    delivery_id: Option<String>,
+    acknowledge_stale: bool,
+) -> tide::Response {
+    let db = &state.db;
+    let delivery_id = delivery_id.as_deref();
 
-impl fmt::Display for RedisError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Error connection to redis")
+    match db.set_invoice_status(&update, delivery_id).await {
+        Ok(cursor) => update.cursor = Some(cursor),
+        Err(InvoiceError::AlreadyExists) => return already_processed(delivery_id),
+        Err(InvoiceError::BadStatusUpdate) if update.status.is_payment() => {
+            match db.get_invoice_status(&update.key).await {
+                Ok(current) if current.is_past_payments() => {
+                    // Recorded against the status the invoice keeps
+                    update.status = current;
+                    return match db.append_invoice_history(&update, delivery_id).await {
+                        Ok(_) => tide::Response::builder(200)
+                            .body(json!({"message": "payment recorded"}))
+                            .build(),
+                        Err(InvoiceError::AlreadyExists) => already_processed(delivery_id),
+                        Err(_) => tide::Response::builder(500).build(),
+                    };
+                }
+                _ => return reject_transition(&update, acknowledge_stale),
+            }
+        }
+        Err(InvoiceError::BadStatusUpdate) => return reject_transition(&update, acknowledge_stale),
+        Err(_) => return tide::Response::builder(500).build(),
     }
+    state.hub.publish(update);
+
+    tide::Response::builder(200)
+        .body(json!({"message": "update synced"}))
+        .build()
 }
 
-impl InvoiceUpdate {
-    fn sync_update(&self, mut connection: redis::Connection) -> Result<(), RedisError> {
-        match connection.set::<String, String, String>(self.invoice_id.clone(), self.status.clone())
-        {
-            Ok(..) => Ok(()),
-            Err(..) => Err(RedisError::Connection),
-        }
+fn already_processed(delivery_id: Option<&str>) -> tide::Response {
+    log::debug!("delivery {:?} already processed", delivery_id);
+    tide::Response::builder(200)
+        .body(json!({"message": "already processed"}))
+        .build()
+}
+
+fn reject_transition(update: &InvoiceUpdate, acknowledge_stale: bool) -> tide::Response {
+    log::warn!(
+        "Rejected {} for invoice {}, illegal status transition",
+        update.status,
+        update.key
+    );
+    if acknowledge_stale {
+        return tide::Response::builder(200)
+            .body(json!({"message": "stale update ignored"}))
+            .build();
     }
+    tide::Response::builder(409)
+        .body(json!({"detail": "illegal status transition"}))
+        .build()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use async_std::sync::{Arc, Mutex};
+    use crate::client::BtcPayClient;
+    use crate::invoice::InvoiceStatus;
+    use crate::mock::{mock_btcpay, mock_state, MockDb};
+    use crate::state::SignatureError;
+    use crate::store::{Store, StoreRegistry, DEFAULT_STORE};
+    use crate::webhook::WebhookEvent;
     use hmac::{Hmac, Mac, NewMac};
-    use std::{collections::HashMap, future::Future};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
     use tide_testing::TideTestingExt;
 
-    #[derive(Clone, Debug)]
-    struct MockDb {
-        invoices: HashMap<String, String>,
+    fn sign_with(secret: &str, body: &serde_json::value::Value) -> String {
+        let mut hmac_sig = HmacSha256::new_varkey(secret.as_bytes()).unwrap();
+        hmac_sig.update(body.to_string().as_bytes());
+        format!("sha256={}", hex::encode(hmac_sig.finalize().into_bytes()))
     }
 
-    #[async_trait]
-    impl InvoiceCommands for MockDb {
-        async fn get_invoice_status(&self, invoice_id: String) -> Result<String, InvoiceError> {
-            match self.invoices.get(&invoice_id) {
-                Some(invoice) => Ok(invoice.clone()),
-                None => Err(InvoiceError::DoesNotExist),
-            }
-        }
+    fn sign(body: &serde_json::value::Value) -> String {
+        sign_with("bob", body)
+    }
 
-        async fn set_invoice_status(
-            &mut self,
-            invoice_id: String,
-            status: String,
-        ) -> Result<(), InvoiceError> {
-            match self.invoices.insert(invoice_id, status) {
-                Some(invoice) => Ok(()),
-                None => Ok(()),
-            }
-        }
+    fn serve(state: State<MockDb>) -> tide::Server<State<MockDb>> {
+        let mut app = tide::with_state(state);
+        app.at("/btcpay").post(handle_btcpay);
+        app.at("/btcpay/:store_id").post(handle_btcpay);
+        app.at("/btcpay/ipn").post(handle_ipn);
+        app.at("/btcpay/:store_id/ipn").post(handle_ipn);
+        app
+    }
+
+    fn app() -> tide::Server<State<MockDb>> {
+        serve(mock_state())
+    }
+
+    fn bob() -> InvoiceKey {
+        InvoiceKey::new(DEFAULT_STORE, "bob")
     }
 
     pub type HmacSha256 = Hmac<sha2::Sha256>;
 
     #[actix_rt::test]
     async fn test_btcpay() {
-        let hashmap: HashMap<String, String> = HashMap::new();
-        let mut state = State {
-            db: Arc::new(Mutex::new(MockDb {
-                invoices: hashmap.clone(),
-            })),
-            hmac: "bob".to_string(),
-        };
-
-        let mut app = tide::with_state(state);
-        app.at("/btcpay").post(handle_btcpay);
+        let app = app();
 
         let update: serde_json::value::Value =
             json!({"invoiceId": "bob", "type": "InvoiceCreated"});
@@ -171,32 +333,457 @@ mod tests {
 
         let sig_string = hex::encode(hmac_sig.finalize().into_bytes());
 
-        let response: serde_json::value::Value = match app
+        let subscription = app.state().hub.subscribe(&bob());
+
+        let response: serde_json::value::Value = app
             .post("/btcpay")
             .body(json!({ "invoiceId": "bob", "type": "InvoiceCreated" }))
             .header("BTCPAY-SIG", format!("sha256={}", sig_string))
             .recv_json()
             .await
-        {
-            Ok(res) => res,
-            Err(_) => {
-                assert!(false);
-                return;
-            }
-        };
+            .expect("request failed");
 
         assert_eq!(response, json!({"message": "update synced"}));
 
         {
             // Check Status Matches Change
-            let invoices = app.state().db.lock().await;
+            let invoices = &app.state().db;
             assert_eq!(
-                invoices
-                    .get_invoice_status("bob".to_string())
+                invoices.get_invoice_status(&bob()).await.unwrap(),
+                InvoiceStatus::Created
+            );
+        }
+
+        // Check Subscribers Were Notified, pointed at the history entry
+        let history = app.state().db.get_invoice_history(&bob()).await.unwrap();
+        assert_eq!(
+            subscription.recv().await,
+            Some(InvoiceUpdate {
+                key: bob(),
+                status: InvoiceStatus::Created,
+                event: Some(WebhookEvent::InvoiceCreated),
+                cursor: Some(history[0].id.clone()),
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_btcpay_unsupported_event() {
+        let app = app();
+
+        let update = json!({"invoiceId": "bob", "type": "InvoicePayed"});
+
+        let response: serde_json::value::Value = app
+            .post("/btcpay")
+            .header("BTCPAY-SIG", sign(&update))
+            .body(update)
+            .recv_json()
+            .await
+            .expect("request failed");
+
+        assert_eq!(response, json!({"message": "unsupported event type"}));
+        assert!(app.state().db.get_invoice_status(&bob()).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_btcpay_redelivery() {
+        let app = app();
+
+        let settled = json!({
+            "deliveryId": "d2",
+            "originalDeliveryId": "d2",
+            "invoiceId": "bob",
+            "type": "InvoiceSettled"
+        });
+        let late_processing = json!({
+            "deliveryId": "d3",
+            "originalDeliveryId": "d1",
+            "isRedelivery": true,
+            "invoiceId": "bob",
+            "type": "InvoiceProcessing"
+        });
+        // The same event again under a fresh deliveryId, Settled may follow
+        // Settled so only the delivery tells them apart
+        let settled_again = json!({
+            "deliveryId": "d4",
+            "originalDeliveryId": "d2",
+            "isRedelivery": true,
+            "invoiceId": "bob",
+            "type": "InvoiceSettled"
+        });
+        let payment = json!({
+            "deliveryId": "d5",
+            "originalDeliveryId": "d5",
+            "invoiceId": "bob",
+            "type": "InvoicePaymentSettled"
+        });
+
+        for (body, expected) in [
+            (settled.clone(), "update synced"),
+            (settled, "already processed"),
+            (settled_again, "already processed"),
+            (late_processing, "stale update ignored"),
+            (payment.clone(), "payment recorded"),
+            (payment, "already processed"),
+        ] {
+            let response: serde_json::value::Value = app
+                .post("/btcpay")
+                .header("BTCPAY-SIG", sign(&body))
+                .body(body)
+                .recv_json()
+                .await
+                .expect("request failed");
+            assert_eq!(response, json!({ "message": expected }));
+        }
+
+        assert_eq!(
+            app.state().db.get_invoice_status(&bob()).await.unwrap(),
+            InvoiceStatus::Settled
+        );
+
+        // Each delivery applied once is in the history, once
+        let history = app.state().db.get_invoice_history(&bob()).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].status, InvoiceStatus::Settled);
+        assert_eq!(history[0].delivery_id.as_deref(), Some("d2"));
+        assert_eq!(history[1].delivery_id.as_deref(), Some("d5"));
+    }
+
+    #[actix_rt::test]
+    async fn test_btcpay_illegal_transition() {
+        let app = app();
+
+        for (body, status) in [
+            (json!({"invoiceId": "bob", "type": "InvoiceSettled"}), 200),
+            (
+                json!({"invoiceId": "bob", "type": "InvoiceProcessing"}),
+                409,
+            ),
+            (json!({"invoiceId": "bob", "type": "InvoiceInvalid"}), 409),
+            (
+                json!({"invoiceId": "bob", "type": "InvoiceInvalid", "manuallyMarked": true}),
+                200,
+            ),
+        ] {
+            let response = app
+                .post("/btcpay")
+                .header("BTCPAY-SIG", sign(&body))
+                .body(body)
+                .await
+                .expect("request failed");
+            assert_eq!(response.status(), status);
+        }
+
+        assert_eq!(
+            app.state().db.get_invoice_status(&bob()).await.unwrap(),
+            InvoiceStatus::Invalid
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_btcpay_late_payments() {
+        for (status, payment, kept) in [
+            (
+                "InvoiceSettled",
+                json!({"invoiceId": "bob", "type": "InvoicePaymentSettled"}),
+                InvoiceStatus::Settled,
+            ),
+            (
+                "InvoiceProcessing",
+                json!({"invoiceId": "bob", "type": "InvoiceReceivedPayment"}),
+                InvoiceStatus::Processing,
+            ),
+            // Paid late, BTCPay keeps the invoice expired as well
+            (
+                "InvoiceExpired",
+                json!({"invoiceId": "bob", "type": "InvoiceReceivedPayment", "afterExpiration": true}),
+                InvoiceStatus::Expired,
+            ),
+            (
+                "InvoiceExpired",
+                json!({"invoiceId": "bob", "type": "InvoicePaymentSettled"}),
+                InvoiceStatus::Expired,
+            ),
+        ] {
+            let app = app();
+            let payment_status = payment["type"].as_str().unwrap().parse().unwrap();
+            for (body, expected) in [
+                (json!({"invoiceId": "bob", "type": status}), "update synced"),
+                (payment, "payment recorded"),
+            ] {
+                let response: serde_json::value::Value = app
+                    .post("/btcpay")
+                    .header("BTCPAY-SIG", sign(&body))
+                    .body(body)
+                    .recv_json()
                     .await
-                    .unwrap(),
-                "InvoiceCreated"
+                    .expect("request failed");
+                assert_eq!(response, json!({ "message": expected }));
+            }
+
+            assert_eq!(
+                app.state().db.get_invoice_status(&bob()).await.unwrap(),
+                kept
+            );
+            let history = app.state().db.get_invoice_history(&bob()).await.unwrap();
+            assert_eq!(history.len(), 2);
+            assert_eq!(history[1].status, kept);
+            assert_eq!(
+                history[1].event.as_ref().and_then(WebhookEvent::status),
+                Some(payment_status)
             );
         }
     }
+
+    #[actix_rt::test]
+    async fn test_btcpay_stores() {
+        let app = app();
+
+        let update = json!({"invoiceId": "bob", "storeId": "alice", "type": "InvoiceCreated"});
+
+        // Signed with the default store's secret instead of alice's
+        let response = app
+            .post("/btcpay/alice")
+            .header("BTCPAY-SIG", sign(&update))
+            .body(update.clone())
+            .await
+            .expect("request failed");
+        assert_eq!(response.status(), 401);
+
+        // Selected through the payload storeId
+        let response = app
+            .post("/btcpay")
+            .header("BTCPAY-SIG", sign_with("carol", &update))
+            .body(update)
+            .await
+            .expect("request failed");
+        assert_eq!(response.status(), 200);
+
+        let response = app
+            .post("/btcpay/mallory")
+            .body(json!({"invoiceId": "bob", "type": "InvoiceCreated"}))
+            .await
+            .expect("request failed");
+        assert_eq!(response.status(), 404);
+
+        let db = &app.state().db;
+        assert_eq!(
+            db.get_invoice_status(&InvoiceKey::new("alice", "bob"))
+                .await
+                .unwrap(),
+            InvoiceStatus::Created
+        );
+        assert!(db.get_invoice_status(&bob()).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_btcpay_secret_rotation() {
+        let app = app();
+
+        let update = json!({"invoiceId": "bob", "type": "InvoiceCreated"});
+        let response = app
+            .post("/btcpay/alice")
+            .header("BTCPAY-SIG", sign_with("dave", &update))
+            .body(update.clone())
+            .await
+            .expect("request failed");
+        assert_eq!(response.status(), 200);
+
+        let update = json!({"invoiceId": "bob", "type": "InvoiceExpired"});
+        let response = app
+            .post("/btcpay/alice")
+            .header("BTCPAY-SIG", sign_with("eve", &update))
+            .body(update.clone())
+            .await
+            .expect("request failed");
+        assert_eq!(response.status(), 401);
+
+        // Rotation finished, carol is swapped out for dave alone
+        let mut stores = StoreRegistry::new();
+        stores.insert(Store::new("alice", "dave"));
+        *app.state().stores.write().unwrap() = stores;
+
+        let response = app
+            .post("/btcpay/alice")
+            .header("BTCPAY-SIG", sign_with("carol", &update))
+            .body(update)
+            .await
+            .expect("request failed");
+        assert_eq!(response.status(), 401);
+    }
+
+    #[actix_rt::test]
+    async fn test_btcpay_replay() {
+        let app = serve(State {
+            max_webhook_age: Some(Duration::from_secs(300)),
+            ..mock_state()
+        });
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let update = json!({
+            "deliveryId": "d1",
+            "timestamp": now,
+            "invoiceId": "bob",
+            "type": "InvoiceCreated"
+        });
+        let response = app
+            .post("/btcpay")
+            .header("BTCPAY-SIG", sign(&update))
+            .body(update.clone())
+            .await
+            .expect("request failed");
+        assert_eq!(response.status(), 200);
+
+        // The same delivery captured and sent again
+        let response: serde_json::value::Value = app
+            .post("/btcpay")
+            .header("BTCPAY-SIG", sign(&update))
+            .body(update)
+            .recv_json()
+            .await
+            .expect("request failed");
+        assert_eq!(response, json!({"detail": "delivery already seen"}));
+
+        let update = json!({
+            "deliveryId": "d2",
+            "timestamp": now - 3600,
+            "invoiceId": "bob",
+            "type": "InvoiceExpired"
+        });
+        let response: serde_json::value::Value = app
+            .post("/btcpay")
+            .header("BTCPAY-SIG", sign(&update))
+            .body(update)
+            .recv_json()
+            .await
+            .expect("request failed");
+        assert_eq!(response, json!({"detail": "stale webhook"}));
+
+        let update = json!({"deliveryId": "d3", "invoiceId": "bob", "type": "InvoiceExpired"});
+        let response: serde_json::value::Value = app
+            .post("/btcpay")
+            .header("BTCPAY-SIG", sign(&update))
+            .body(update)
+            .recv_json()
+            .await
+            .expect("request failed");
+        assert_eq!(response, json!({"detail": "missing timestamp"}));
+
+        assert_eq!(
+            app.state().db.get_invoice_status(&bob()).await.unwrap(),
+            InvoiceStatus::Created
+        );
+    }
+
+    #[test]
+    fn test_signature_errors() {
+        let app = app();
+        let state = app.state();
+        let store = state.select_store(None, None).unwrap();
+        let body = json!({"invoiceId": "bob", "type": "InvoiceCreated"});
+        let bytes = body.to_string().into_bytes();
+
+        let verify = |header: Option<&str>| state.verify_signature(&store, &bytes, header);
+
+        assert_eq!(verify(Some(&sign(&body))), Ok(()));
+        assert_eq!(verify(None), Err(SignatureError::Missing));
+        assert_eq!(verify(Some("sha256")), Err(SignatureError::Malformed));
+        assert_eq!(verify(Some("sha256=zz")), Err(SignatureError::Malformed));
+        assert_eq!(verify(Some("sha256=abcd")), Err(SignatureError::Malformed));
+        assert_eq!(
+            verify(Some(&sign(&body).replace("sha256", "sha1"))),
+            Err(SignatureError::UnsupportedAlgorithm)
+        );
+        assert_eq!(
+            verify(Some(&sign_with("carol", &body))),
+            Err(SignatureError::Mismatch)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_btcpay_ipn() {
+        let url = mock_btcpay(json!({
+            "invoices/bob": {"data": {"id": "bob", "status": "paid", "exceptionStatus": false}},
+            "invoices/carol": {"data": {"id": "carol", "status": "new", "exceptionStatus": "paidPartial"}}
+        }))
+        .await;
+        let app = serve(State {
+            btcpay: Some(BtcPayClient::new(&url, Duration::from_secs(5)).unwrap()),
+            ..mock_state()
+        });
+        let subscription = app.state().hub.subscribe(&bob());
+
+        // The posted status is not trusted, BTCPay says bob is paid
+        let response: serde_json::value::Value = app
+            .post("/btcpay/ipn")
+            .body(json!({"id": "bob", "status": "complete"}))
+            .recv_json()
+            .await
+            .expect("request failed");
+        assert_eq!(response, json!({"message": "update synced"}));
+        assert_eq!(
+            subscription.recv().await.map(|update| update.status),
+            Some(InvoiceStatus::Processing)
+        );
+
+        // Extended notification for a store
+        let response = app
+            .post("/btcpay/alice/ipn")
+            .body(json!({
+                "event": {"code": 1002, "name": "invoice_receivedPayment"},
+                "data": {"id": "carol", "status": "new"}
+            }))
+            .await
+            .expect("request failed");
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            app.state()
+                .db
+                .get_invoice_status(&InvoiceKey::new("alice", "carol"))
+                .await
+                .unwrap(),
+            InvoiceStatus::ReceivedPayment
+        );
+
+        let response = app
+            .post("/btcpay/ipn")
+            .body(json!({"id": "mallory", "status": "complete"}))
+            .await
+            .expect("request failed");
+        assert_eq!(response.status(), 404);
+
+        // Stores have to be configured, even without a signature to check
+        let response = app
+            .post("/btcpay/mallory/ipn")
+            .body(json!({"id": "bob", "status": "complete"}))
+            .await
+            .expect("request failed");
+        assert_eq!(response.status(), 404);
+        assert!(app
+            .state()
+            .db
+            .get_invoice_status(&InvoiceKey::new("mallory", "bob"))
+            .await
+            .is_err());
+
+        // Only --store given, nothing to file bare IPNs under
+        let mut stores = StoreRegistry::new();
+        stores.insert(Store::new("alice", "carol"));
+        *app.state().stores.write().unwrap() = stores;
+        let response = app
+            .post("/btcpay/ipn")
+            .body(json!({"id": "carol", "status": "new"}))
+            .await
+            .expect("request failed");
+        assert_eq!(response.status(), 404);
+        assert!(app
+            .state()
+            .db
+            .get_invoice_status(&InvoiceKey::new(DEFAULT_STORE, "carol"))
+            .await
+            .is_err());
+    }
 }
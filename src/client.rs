@@ -0,0 +1,253 @@
+use super::invoice::{InvoiceKey, InvoiceStatus, InvoiceTerms};
+use super::store::DEFAULT_STORE;
+use super::webhook::LegacyInvoice;
+use serde::Deserialize;
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, PartialEq)]
+pub enum ClientError {
+    /// BTCPay could not be reached or answered with an error.
+    Request,
+    /// BTCPay does not know the invoice.
+    NotFound,
+    /// BTCPay answered with something other than an invoice.
+    BadResponse,
+    /// No API key, or no BTCPay store id for the invoice's store.
+    NotConfigured,
+}
+
+/// Client for the BTCPay Server instance the stores live on, used to check
+/// notifications that can't be verified on their own.
+#[derive(Clone, Debug)]
+pub struct BtcPayClient {
+    client: surf::Client,
+    base_url: surf::Url,
+    api_key: Option<String>,
+    default_store: Option<String>,
+}
+
+/// Legacy API responses wrap the invoice in `data`.
+#[derive(Deserialize)]
+struct LegacyResponse {
+    data: LegacyInvoice,
+}
+
+/// What a Greenfield lookup tells about an invoice.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvoiceDetails {
+    pub status: InvoiceStatus,
+    pub terms: InvoiceTerms,
+}
+
+/// The parts of a Greenfield invoice this service tracks.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GreenfieldInvoice {
+    status: String,
+    #[serde(default)]
+    additional_status: Option<String>,
+    /// Unix time, in seconds like the rest.
+    #[serde(default)]
+    expiration_time: Option<u64>,
+    #[serde(default)]
+    monitoring_expiration: Option<u64>,
+    #[serde(default)]
+    checkout: Option<GreenfieldCheckout>,
+    #[serde(default)]
+    amount: Option<String>,
+    #[serde(default)]
+    currency: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GreenfieldCheckout {
+    #[serde(default)]
+    monitoring_minutes: Option<u64>,
+}
+
+impl GreenfieldInvoice {
+    /// Older BTCPay versions only give the expiry and the store's
+    /// monitoring window.
+    fn monitoring_ends(&self) -> Option<SystemTime> {
+        let monitoring_minutes = self
+            .checkout
+            .as_ref()
+            .and_then(|checkout| checkout.monitoring_minutes);
+        let ends = match (self.monitoring_expiration, self.expiration_time) {
+            (Some(ends), _) => ends,
+            (None, Some(expires)) => expires + monitoring_minutes? * 60,
+            (None, None) => return None,
+        };
+        Some(UNIX_EPOCH + Duration::from_secs(ends))
+    }
+
+    fn status(&self) -> Option<InvoiceStatus> {
+        match (self.status.as_str(), self.additional_status.as_deref()) {
+            ("New", Some("PaidPartial")) => Some(InvoiceStatus::ReceivedPayment),
+            ("New", _) => Some(InvoiceStatus::Created),
+            ("Processing", _) => Some(InvoiceStatus::Processing),
+            ("Settled", _) => Some(InvoiceStatus::Settled),
+            ("Expired", _) => Some(InvoiceStatus::Expired),
+            ("Invalid", _) => Some(InvoiceStatus::Invalid),
+            _ => None,
+        }
+    }
+}
+
+impl BtcPayClient {
+    pub fn new(base_url: &str, timeout: Duration) -> Result<BtcPayClient, String> {
+        // Without the trailing slash the last path segment would be replaced
+        // rather than extended.
+        let mut base_url = base_url.to_string();
+        if !base_url.ends_with('/') {
+            base_url.push('/');
+        }
+        let base_url = surf::Url::parse(&base_url).map_err(|e| e.to_string())?;
+        if base_url.cannot_be_a_base() {
+            return Err(format!("{} can't have paths", base_url));
+        }
+
+        let client = surf::Config::new()
+            .set_base_url(base_url.clone())
+            .set_timeout(Some(timeout))
+            .try_into()
+            .expect("surf client config is infallible");
+        Ok(BtcPayClient {
+            client,
+            base_url,
+            api_key: None,
+            default_store: None,
+        })
+    }
+
+    /// Greenfield API key, needs `btcpay.store.canviewinvoices`.
+    pub fn with_api_key(mut self, api_key: &str) -> BtcPayClient {
+        self.api_key = Some(api_key.to_string());
+        self
+    }
+
+    /// BTCPay store id of the default store, other stores are configured
+    /// under their BTCPay ids already.
+    pub fn with_default_store(mut self, store_id: &str) -> BtcPayClient {
+        self.default_store = Some(store_id.to_string());
+        self
+    }
+
+    /// Whether Greenfield lookups are possible at all.
+    pub fn has_api_key(&self) -> bool {
+        self.api_key.is_some()
+    }
+
+    /// Fetches an invoice from the Greenfield API.
+    pub async fn get_invoice(&self, key: &InvoiceKey) -> Result<InvoiceDetails, ClientError> {
+        let api_key = self.api_key.as_ref().ok_or(ClientError::NotConfigured)?;
+        let store_id = match key.store_id.as_str() {
+            DEFAULT_STORE => self
+                .default_store
+                .as_ref()
+                .ok_or(ClientError::NotConfigured)?,
+            store_id => store_id,
+        };
+
+        let url = self.endpoint(&["api", "v1", "stores", store_id, "invoices", &key.invoice_id])?;
+        let request = self
+            .client
+            .get(url)
+            .header("Authorization", format!("token {}", api_key));
+        let invoice: GreenfieldInvoice = self.fetch(request, key).await?;
+
+        let status = invoice.status().ok_or_else(|| {
+            log::error!(
+                "Unknown BTCPay status {} for invoice {}",
+                invoice.status,
+                key
+            );
+            ClientError::BadResponse
+        })?;
+        Ok(InvoiceDetails {
+            status,
+            terms: InvoiceTerms {
+                monitoring_ends: invoice.monitoring_ends(),
+                amount: invoice.amount,
+                currency: invoice.currency,
+            },
+        })
+    }
+
+    /// Fetches an invoice from the legacy Bitpay-compatible API.
+    pub async fn get_legacy_invoice(&self, invoice_id: &str) -> Result<LegacyInvoice, ClientError> {
+        let request = self.client.get(self.endpoint(&["invoices", invoice_id])?);
+        let response: LegacyResponse = self.fetch(request, invoice_id).await?;
+        Ok(response.data)
+    }
+
+    /// `segments` under the base URL, each percent-encoded. The ids in them
+    /// come from unauthenticated requests, ones that could still reach
+    /// another endpoint are refused as unknown.
+    fn endpoint(&self, segments: &[&str]) -> Result<surf::Url, ClientError> {
+        if segments.iter().any(|segment| {
+            segment.is_empty() || matches!(*segment, "." | "..") || segment.contains(['/', '\\'])
+        }) {
+            log::debug!("Refusing to look up {:?} on BTCPay", segments);
+            return Err(ClientError::NotFound);
+        }
+
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("base URL checked in new")
+            .pop_if_empty()
+            .extend(segments);
+        Ok(url)
+    }
+
+    async fn fetch<R: serde::de::DeserializeOwned>(
+        &self,
+        request: surf::RequestBuilder,
+        invoice: impl std::fmt::Display,
+    ) -> Result<R, ClientError> {
+        let mut response = request
+            .header("Accept", "application/json")
+            .await
+            .map_err(|e| {
+                log::error!("BTCPay request failed '{}'", e);
+                ClientError::Request
+            })?;
+
+        match response.status() {
+            status if status.is_success() => {}
+            surf::StatusCode::NotFound => return Err(ClientError::NotFound),
+            status => {
+                log::error!("BTCPay answered {} for invoice {}", status, invoice);
+                return Err(ClientError::Request);
+            }
+        }
+
+        response.body_json::<R>().await.map_err(|e| {
+            log::error!("Unexpected BTCPay response '{}'", e);
+            ClientError::BadResponse
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint() {
+        let client =
+            BtcPayClient::new("https://btcpay.example/base", Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            client.endpoint(&["invoices", "x?y#z%"]).unwrap().as_str(),
+            "https://btcpay.example/base/invoices/x%3Fy%23z%25"
+        );
+        for invoice_id in ["", ".", "..", "../../api/v1/users", "a\\b"] {
+            assert_eq!(
+                client.endpoint(&["invoices", invoice_id]),
+                Err(ClientError::NotFound)
+            );
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use super::invoice::{InvoiceKey, InvoiceUpdate};
+use async_std::channel::{self, Receiver, Sender};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The senders of every subscription watching one invoice, by subscription id.
+type Subscribers = Vec<(u64, Sender<InvoiceUpdate>)>;
+
+/// In-process fan-out of invoice status changes to open connections.
+///
+/// Subscribers are keyed by invoice so a publish only wakes the
+/// connections watching that invoice.
+#[derive(Clone, Default)]
+pub struct Hub {
+    subscribers: Arc<Mutex<HashMap<InvoiceKey, Subscribers>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Hub {
+    pub fn new() -> Hub {
+        Hub::default()
+    }
+
+    /// A subscription to no invoices yet, see `Subscription::add`.
+    pub fn subscription(&self) -> Subscription {
+        let (sender, receiver) = channel::unbounded();
+        Subscription {
+            hub: self.clone(),
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            keys: HashSet::new(),
+            sender,
+            receiver,
+        }
+    }
+
+    pub fn subscribe(&self, key: &InvoiceKey) -> Subscription {
+        let mut subscription = self.subscription();
+        subscription.add(key);
+        subscription
+    }
+
+    pub fn publish(&self, update: InvoiceUpdate) {
+        let mut subscribers = self.subscribers.lock().expect("hub lock poisoned");
+        if let Some(senders) = subscribers.get_mut(&update.key) {
+            senders.retain(|(_, sender)| sender.try_send(update.clone()).is_ok());
+            if senders.is_empty() {
+                subscribers.remove(&update.key);
+            }
+        }
+    }
+
+    /// Whether any subscription is watching `key`.
+    #[cfg(test)]
+    pub fn is_watched(&self, key: &InvoiceKey) -> bool {
+        self.subscribers
+            .lock()
+            .expect("hub lock poisoned")
+            .contains_key(key)
+    }
+
+    fn register(&self, key: &InvoiceKey, id: u64, sender: Sender<InvoiceUpdate>) {
+        self.subscribers
+            .lock()
+            .expect("hub lock poisoned")
+            .entry(key.clone())
+            .or_default()
+            .push((id, sender));
+    }
+
+    fn unregister(&self, key: &InvoiceKey, id: u64) {
+        let mut subscribers = self.subscribers.lock().expect("hub lock poisoned");
+        if let Some(senders) = subscribers.get_mut(key) {
+            senders.retain(|(sender_id, _)| *sender_id != id);
+            if senders.is_empty() {
+                subscribers.remove(key);
+            }
+        }
+    }
+}
+
+/// Receiving end of a hub subscription to any number of invoices, all
+/// unregistered when dropped.
+pub struct Subscription {
+    hub: Hub,
+    id: u64,
+    keys: HashSet<InvoiceKey>,
+    sender: Sender<InvoiceUpdate>,
+    receiver: Receiver<InvoiceUpdate>,
+}
+
+impl Subscription {
+    /// Starts receiving updates for `key`, false if already subscribed.
+    pub fn add(&mut self, key: &InvoiceKey) -> bool {
+        if !self.keys.insert(key.clone()) {
+            return false;
+        }
+        self.hub.register(key, self.id, self.sender.clone());
+        true
+    }
+
+    /// Stops receiving updates for `key`. Ones already queued are still
+    /// returned by `recv`.
+    pub fn remove(&mut self, key: &InvoiceKey) -> bool {
+        if !self.keys.remove(key) {
+            return false;
+        }
+        self.hub.unregister(key, self.id);
+        true
+    }
+
+    pub async fn recv(&self) -> Option<InvoiceUpdate> {
+        self.receiver.recv().await.ok()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.receiver.close();
+        for key in &self.keys {
+            self.hub.unregister(key, self.id);
+        }
+    }
+}
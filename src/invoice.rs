@@ -1,6 +1,10 @@
+use super::webhook::WebhookEvent;
 use async_trait::async_trait;
-use std::{error::Error, fmt, future::Future};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+use std::{error::Error, fmt, str::FromStr};
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum InvoiceError {
     DbConnection,
@@ -19,42 +23,251 @@ impl fmt::Display for InvoiceError {
     }
 }
 
+/// Invoice status, named after the BTCPay webhook event that produced it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum InvoiceStatus {
+    #[serde(rename = "InvoiceCreated")]
     Created,
-    PartiallyPayed,
-    Payed,
+    #[serde(rename = "InvoiceReceivedPayment")]
+    ReceivedPayment,
+    #[serde(rename = "InvoiceProcessing")]
+    Processing,
+    #[serde(rename = "InvoiceSettled")]
+    Settled,
+    #[serde(rename = "InvoiceExpired")]
     Expired,
+    #[serde(rename = "InvoiceInvalid")]
+    Invalid,
+    #[serde(rename = "InvoicePaymentSettled")]
+    PaymentSettled,
 }
 
 impl fmt::Display for InvoiceStatus {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             InvoiceStatus::Created => write!(f, "InvoiceCreated"),
+            InvoiceStatus::ReceivedPayment => write!(f, "InvoiceReceivedPayment"),
+            InvoiceStatus::Processing => write!(f, "InvoiceProcessing"),
+            InvoiceStatus::Settled => write!(f, "InvoiceSettled"),
             InvoiceStatus::Expired => write!(f, "InvoiceExpired"),
-            InvoiceStatus::PartiallyPayed => write!(f, "InvoiceRecievedPayment"),
-            InvoiceStatus::Payed => write!(f, "InvoicePayed"),
+            InvoiceStatus::Invalid => write!(f, "InvoiceInvalid"),
+            InvoiceStatus::PaymentSettled => write!(f, "InvoicePaymentSettled"),
         }
     }
 }
 
-impl InvoiceStatus {
+impl FromStr for InvoiceStatus {
+    type Err = InvoiceError;
+
     fn from_str(status: &str) -> Result<InvoiceStatus, InvoiceError> {
         match status {
             "InvoiceCreated" => Ok(InvoiceStatus::Created),
+            "InvoiceReceivedPayment" => Ok(InvoiceStatus::ReceivedPayment),
+            "InvoiceProcessing" => Ok(InvoiceStatus::Processing),
+            "InvoiceSettled" => Ok(InvoiceStatus::Settled),
             "InvoiceExpired" => Ok(InvoiceStatus::Expired),
-            "InvoiceRecievedPayment" => Ok(InvoiceStatus::PartiallyPayed),
-            "InvoicePayed" => Ok(InvoiceStatus::Payed),
+            "InvoiceInvalid" => Ok(InvoiceStatus::Invalid),
+            "InvoicePaymentSettled" => Ok(InvoiceStatus::PaymentSettled),
             _ => Err(InvoiceError::BadStatus),
         }
     }
 }
 
+impl InvoiceStatus {
+    /// Whether the invoice is done and no further updates are expected.
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            InvoiceStatus::Settled | InvoiceStatus::Expired | InvoiceStatus::Invalid
+        )
+    }
+
+    /// Whether the status only reports a payment. BTCPay keeps sending these
+    /// after the invoice itself moved on, for overpayments or payments
+    /// confirming alongside `InvoiceSettled`.
+    pub fn is_payment(&self) -> bool {
+        matches!(
+            self,
+            InvoiceStatus::ReceivedPayment | InvoiceStatus::PaymentSettled
+        )
+    }
+
+    /// Whether the invoice is past taking payments, those arriving anyway
+    /// are recorded in its history without changing its status. That covers
+    /// payments BTCPay flags `afterExpiration`, it keeps such invoices
+    /// expired too.
+    pub fn is_past_payments(&self) -> bool {
+        *self == InvoiceStatus::Processing || self.is_terminal()
+    }
+
+    /// The transition table: statuses an invoice may be in for `event` to
+    /// move it to `self`. Invoices not seen before may take any status, their
+    /// earlier events may simply have been missed.
+    pub fn allowed_from(&self, event: Option<&WebhookEvent>) -> Vec<InvoiceStatus> {
+        use InvoiceStatus::*;
+
+        let manually_marked = matches!(
+            event,
+            Some(WebhookEvent::InvoiceSettled {
+                manually_marked: true,
+                ..
+            }) | Some(WebhookEvent::InvoiceInvalid {
+                manually_marked: true,
+                ..
+            })
+        );
+
+        let mut allowed = match self {
+            Created => vec![Created],
+            ReceivedPayment | PaymentSettled => vec![Created, ReceivedPayment, PaymentSettled],
+            Processing => vec![Created, ReceivedPayment, PaymentSettled, Processing],
+            Settled => vec![
+                Created,
+                ReceivedPayment,
+                PaymentSettled,
+                Processing,
+                Settled,
+            ],
+            Expired => vec![Created, ReceivedPayment, PaymentSettled, Expired],
+            Invalid => vec![
+                ReceivedPayment,
+                PaymentSettled,
+                Processing,
+                Expired,
+                Invalid,
+            ],
+        };
+
+        // Merchants can override a final status from the BTCPay UI.
+        if manually_marked {
+            match self {
+                Settled => allowed.extend(&[Expired, Invalid]),
+                Invalid => allowed.push(Settled),
+                _ => {}
+            }
+        }
+
+        allowed
+    }
+}
+
+/// Identifies an invoice, invoice ids are only unique within a store.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InvoiceKey {
+    pub store_id: String,
+    pub invoice_id: String,
+}
+
+impl InvoiceKey {
+    pub fn new(store_id: &str, invoice_id: &str) -> InvoiceKey {
+        InvoiceKey {
+            store_id: store_id.to_string(),
+            invoice_id: invoice_id.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for InvoiceKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.store_id, self.invoice_id)
+    }
+}
+
+/// A status change for an invoice, along with the webhook event behind it
+/// when there is one.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InvoiceUpdate {
+    #[serde(flatten)]
+    pub key: InvoiceKey,
+    pub status: InvoiceStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<WebhookEvent>,
+    /// The id of the change in the invoice's history, once recorded there.
+    /// Clients pass it back as `since` to catch up after reconnecting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+}
+
+/// One accepted status change in an invoice's history.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    /// Position in the history, later entries sort after earlier ones.
+    pub id: String,
+    /// Unix time in milliseconds the change was accepted.
+    pub received_at: u64,
+    pub status: InvoiceStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivery_id: Option<String>,
+    /// The webhook event, with its payment details, when there is one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<WebhookEvent>,
+}
+
+impl HistoryEntry {
+    /// The entry as the update it recorded.
+    pub fn to_update(&self, key: &InvoiceKey) -> InvoiceUpdate {
+        InvoiceUpdate {
+            key: key.clone(),
+            status: self.status,
+            event: self.event.clone(),
+            cursor: Some(self.id.clone()),
+        }
+    }
+}
+
+/// What BTCPay says an invoice is for and how long it watches it, kept with
+/// the invoice's status once looked up.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InvoiceTerms {
+    /// What the invoice is for, as the decimal string BTCPay gives.
+    pub amount: Option<String>,
+    pub currency: Option<String>,
+    /// When BTCPay stops watching the invoice for payments, its expiry plus
+    /// the monitoring window.
+    pub monitoring_ends: Option<SystemTime>,
+}
+
 #[async_trait]
-pub trait InvoiceCommands {
-    async fn get_invoice_status(&self, invoice_id: String) -> Result<String, InvoiceError>;
+pub trait InvoiceCommands: Send + Sync {
+    async fn get_invoice_status(&self, key: &InvoiceKey) -> Result<InvoiceStatus, InvoiceError>;
+    /// Applies an update the transition table allows, recording it, and the
+    /// delivery behind it, in the invoice's history and marking the delivery
+    /// processed in the same step. Returns the new history entry's id, or
+    /// `InvoiceError::AlreadyExists` for a delivery already processed.
     async fn set_invoice_status(
-        &mut self,
-        invoice_id: String,
-        status: String,
+        &self,
+        update: &InvoiceUpdate,
+        delivery_id: Option<&str>,
+    ) -> Result<String, InvoiceError>;
+    /// Records a delivery id for `ttl`, false if it was already recorded.
+    async fn claim_delivery(&self, delivery_id: &str, ttl: Duration) -> Result<bool, InvoiceError>;
+    /// Forgets a claimed delivery id so a retry of it is accepted.
+    async fn release_delivery(&self, delivery_id: &str) -> Result<(), InvoiceError>;
+    /// Records what BTCPay says about an invoice already stored, leaving the
+    /// terms it didn't give as they were.
+    async fn set_invoice_terms(
+        &self,
+        key: &InvoiceKey,
+        terms: &InvoiceTerms,
     ) -> Result<(), InvoiceError>;
+    /// The terms recorded for an invoice, empty ones if none were.
+    async fn get_invoice_terms(&self, key: &InvoiceKey) -> Result<InvoiceTerms, InvoiceError>;
+    /// Invoices whose last known status is not terminal.
+    async fn pending_invoices(&self) -> Result<Vec<InvoiceKey>, InvoiceError>;
+    /// Records an update, and the delivery behind it, in the invoice's
+    /// history without applying it. Returns the new entry's id, or
+    /// `InvoiceError::AlreadyExists` for a delivery already processed.
+    async fn append_invoice_history(
+        &self,
+        update: &InvoiceUpdate,
+        delivery_id: Option<&str>,
+    ) -> Result<String, InvoiceError>;
+    /// Every recorded change of the invoice, oldest first.
+    async fn get_invoice_history(
+        &self,
+        key: &InvoiceKey,
+    ) -> Result<Vec<HistoryEntry>, InvoiceError>;
 }
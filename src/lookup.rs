@@ -0,0 +1,229 @@
+use super::invoice::{InvoiceCommands, InvoiceError, InvoiceKey, InvoiceStatus, InvoiceUpdate};
+use super::protocol::invoice_key;
+use super::reconcile;
+use super::state::State;
+use async_std::future;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+use tide::convert::json;
+
+/// Longest `/wait` holds a request, whatever its `timeout`.
+const MAX_WAIT: Duration = Duration::from_secs(120);
+
+#[derive(Deserialize)]
+struct LookupQuery {
+    store_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WaitQuery {
+    store_id: Option<String>,
+    /// The status the client already has.
+    since: Option<InvoiceStatus>,
+    /// Seconds to wait for another, 30 by default.
+    timeout: Option<u64>,
+}
+
+/// `GET /invoices/:invoice_id`, the invoice's current status.
+pub async fn get_invoice<T: InvoiceCommands + std::clone::Clone>(
+    req: tide::Request<State<T>>,
+) -> tide::Result<tide::Response> {
+    let query = req.query::<LookupQuery>()?;
+    let key = invoice_key(query.store_id.as_deref(), req.param("invoice_id")?);
+
+    let status = current_status(req.state(), &key).await?;
+    Ok(status_response(key, status))
+}
+
+/// `GET /invoices/:invoice_id/wait?since=&timeout=`, long-polls until the
+/// invoice's status differs from `since`, answering with the status it has
+/// when that happens or the timeout elapses. Without `since`, waits for the
+/// invoice to have any status.
+pub async fn wait_invoice<T: InvoiceCommands + std::clone::Clone>(
+    req: tide::Request<State<T>>,
+) -> tide::Result<tide::Response> {
+    let query = req.query::<WaitQuery>()?;
+    let key = invoice_key(query.store_id.as_deref(), req.param("invoice_id")?);
+    let timeout = Duration::from_secs(query.timeout.unwrap_or(30)).min(MAX_WAIT);
+    let deadline = Instant::now() + timeout;
+    let state = req.state();
+
+    // Already listening when the status is read, or a change right after
+    // would leave the poll waiting out its timeout
+    let subscription = state.hub.subscribe(&key);
+    let mut status = current_status(state, &key).await?;
+
+    while status.is_none() || status == query.since {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match future::timeout(remaining, subscription.recv()).await {
+            Ok(Some(update)) => status = Some(update.status),
+            Ok(None) | Err(_) => break,
+        }
+    }
+
+    Ok(status_response(key, status))
+}
+
+/// `GET /invoices/:invoice_id/history`, every recorded status change of the
+/// invoice, oldest first.
+pub async fn get_history<T: InvoiceCommands + std::clone::Clone>(
+    req: tide::Request<State<T>>,
+) -> tide::Result<tide::Response> {
+    let query = req.query::<LookupQuery>()?;
+    let key = invoice_key(query.store_id.as_deref(), req.param("invoice_id")?);
+    let db = &req.state().db;
+
+    let history = db.get_invoice_history(&key).await.map_err(|e| {
+        log::error!("Failed to read history of invoice {}, {}", key, e);
+        tide::Error::from_str(500, "failed to read invoice history")
+    })?;
+    // Invoices from before histories were kept have none
+    if history.is_empty() {
+        if let Err(InvoiceError::DoesNotExist) = db.get_invoice_status(&key).await {
+            return Ok(status_response(key, None));
+        }
+    }
+
+    Ok(tide::Response::builder(200)
+        .body(json!({
+            "storeId": key.store_id,
+            "invoiceId": key.invoice_id,
+            "history": history,
+        }))
+        .build())
+}
+
+/// The invoice's status, `None` if it has none yet. Unknown invoices are
+/// looked up on BTCPay when an API key is configured.
+async fn current_status<T: InvoiceCommands + std::clone::Clone>(
+    state: &State<T>,
+    key: &InvoiceKey,
+) -> tide::Result<Option<InvoiceStatus>> {
+    match state.db.get_invoice_status(key).await {
+        Ok(status) => Ok(Some(status)),
+        Err(InvoiceError::DoesNotExist) => match &state.btcpay {
+            Some(client) if client.has_api_key() => {
+                Ok(reconcile::refresh(&*state.db, &state.hub, client, key).await)
+            }
+            _ => Ok(None),
+        },
+        Err(e) => {
+            log::error!("Failed to read status of invoice {}, {}", key, e);
+            Err(tide::Error::from_str(500, "failed to read invoice status"))
+        }
+    }
+}
+
+fn status_response(key: InvoiceKey, status: Option<InvoiceStatus>) -> tide::Response {
+    match status {
+        Some(status) => tide::Response::builder(200)
+            .body(json!(InvoiceUpdate {
+                key,
+                status,
+                event: None,
+                cursor: None,
+            }))
+            .build(),
+        None => tide::Response::builder(404)
+            .body(json!({"detail": "unknown invoice"}))
+            .build(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{mock_state, publish, MockDb};
+    use crate::store::DEFAULT_STORE;
+    use crate::webhook::WebhookEvent;
+    use tide_testing::TideTestingExt;
+
+    fn app(state: State<MockDb>) -> tide::Server<State<MockDb>> {
+        let mut app = tide::with_state(state);
+        app.at("/invoices/:invoice_id").get(get_invoice);
+        app.at("/invoices/:invoice_id/wait").get(wait_invoice);
+        app.at("/invoices/:invoice_id/history").get(get_history);
+        app
+    }
+
+    #[actix_rt::test]
+    async fn test_history() {
+        let state = mock_state();
+        let app = app(state.clone());
+        let response = app.get("/invoices/bob/history").await.unwrap();
+        assert_eq!(response.status(), 404);
+
+        let update = InvoiceUpdate {
+            key: InvoiceKey::new(DEFAULT_STORE, "bob"),
+            status: InvoiceStatus::Settled,
+            event: Some(WebhookEvent::InvoiceSettled {
+                manually_marked: false,
+                over_paid: true,
+            }),
+            cursor: None,
+        };
+        state
+            .db
+            .set_invoice_status(&update, Some("d1"))
+            .await
+            .unwrap();
+
+        let mut body: serde_json::Value =
+            app.get("/invoices/bob/history").recv_json().await.unwrap();
+        let entry = body["history"][0].as_object_mut().unwrap();
+        assert!(entry.remove("id").unwrap().is_string());
+        assert!(entry.remove("receivedAt").unwrap().as_u64().unwrap() > 0);
+        assert_eq!(
+            body,
+            json!({
+                "storeId": "default",
+                "invoiceId": "bob",
+                "history": [{
+                    "status": "InvoiceSettled",
+                    "deliveryId": "d1",
+                    "event": {"type": "InvoiceSettled", "manuallyMarked": false, "overPaid": true}
+                }]
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_lookup() {
+        let state = mock_state();
+        let app = app(state.clone());
+
+        let response = app.get("/invoices/bob").await.unwrap();
+        assert_eq!(response.status(), 404);
+        let response = app.get("/invoices/bob/wait?timeout=0").await.unwrap();
+        assert_eq!(response.status(), 404);
+        let response = app.get("/invoices/bob/wait?since=Paid").await.unwrap();
+        assert_eq!(response.status(), 400);
+
+        publish(&state, "bob", InvoiceStatus::Created).await;
+        let body: serde_json::Value = app.get("/invoices/bob").recv_json().await.unwrap();
+        assert_eq!(
+            body,
+            json!({"storeId": "default", "invoiceId": "bob", "status": "InvoiceCreated"})
+        );
+
+        // Unchanged by the timeout
+        let body: serde_json::Value = app
+            .get("/invoices/bob/wait?since=InvoiceCreated&timeout=0")
+            .recv_json()
+            .await
+            .unwrap();
+        assert_eq!(body["status"], "InvoiceCreated");
+
+        let waiting = state.clone();
+        async_std::task::spawn(async move {
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            publish(&waiting, "bob", InvoiceStatus::Processing).await;
+        });
+        let body: serde_json::Value = app
+            .get("/invoices/bob/wait?since=InvoiceCreated")
+            .recv_json()
+            .await
+            .unwrap();
+        assert_eq!(body["status"], "InvoiceProcessing");
+    }
+}
@@ -1,43 +1,213 @@
-use async_std::sync::{Arc, Mutex};
+use async_std::sync::Arc;
+use async_std::task;
+use std::sync::RwLock;
+use std::time::Duration;
 use tide_websockets::WebSocket;
 
 mod args;
 mod btcpay;
+mod client;
 mod database;
+mod hub;
 mod invoice;
+mod lookup;
+#[cfg(test)]
+mod mock;
+mod protocol;
+mod reconcile;
+mod sse;
 mod state;
+mod store;
+mod watcher;
+mod webhook;
 mod websocket;
 
 #[async_std::main]
 async fn main() -> tide::Result<()> {
     let matches = args::get_args().get_matches();
-    let hmac = matches
-        .value_of("btcpay-hmac")
-        .expect("Missing argument hmac");
-    let host = matches
-        .value_of("redis-host")
-        .expect("Missing argument host");
+    let host = matches.value_of("redis-host").unwrap_or("127.0.0.1");
     let port = matches.value_of("redis-port").unwrap_or("6379");
     let pass = matches.value_of("redis-password").unwrap_or("");
+    let fan_out = matches.is_present("fan-out");
+    let mut pool = database::PoolOptions::default();
+    if let Some(size) = matches.value_of("redis-pool-size") {
+        pool.size = size.parse().expect("Invalid redis pool size");
+    }
+    if let Some(timeout) = matches.value_of("redis-timeout") {
+        pool.timeout = Duration::from_millis(timeout.parse().expect("Invalid redis timeout"));
+    }
+
+    let db = match matches.value_of("redis-url") {
+        Some(url) => database::RedisDb::from_url(url, pool).await?,
+        None => {
+            database::RedisDb::new(host.to_string(), port.to_string(), pass.to_string(), pool)
+                .await?
+        }
+    }
+    .with_fan_out(fan_out)
+    .with_key_prefix(
+        matches
+            .value_of("key-prefix")
+            .unwrap_or(database::DEFAULT_KEY_PREFIX),
+    );
+
+    if matches.subcommand_matches("migrate").is_some() {
+        let migration = db.migrate().await?;
+        println!(
+            "Migrated {} invoices, skipped {} already migrated",
+            migration.invoices, migration.skipped
+        );
+        return Ok(());
+    }
+
+    let mut stores = store::StoreRegistry::new();
+    if let Some(hmac) = matches.value_of("btcpay-hmac") {
+        stores.insert(store::Store::new(store::DEFAULT_STORE, hmac));
+    }
+    for value in matches.values_of("store").into_iter().flatten() {
+        stores.insert(value.parse().expect("Invalid store"));
+    }
+    let store_config = store::StoreConfig {
+        stores,
+        secrets_file: matches.value_of("secrets-file").map(str::to_string),
+    };
+    let stores = store_config.load().expect("Invalid secrets file");
+    if stores.is_empty() {
+        panic!("Missing argument hmac, store or secrets-file");
+    }
+    let stores = Arc::new(RwLock::new(stores));
+    let db = match matches.value_of("delivery-ttl") {
+        Some(ttl) => db.with_delivery_ttl(Duration::from_secs(
+            ttl.parse().expect("Invalid delivery ttl"),
+        )),
+        None => db,
+    };
+    let terminal_ttl = match matches.value_of("terminal-ttl") {
+        Some(ttl) => ttl.parse().expect("Invalid terminal ttl"),
+        None => 7 * 24 * 60 * 60,
+    };
+    let pending_ttl = match matches.value_of("pending-ttl") {
+        Some(ttl) => ttl.parse().expect("Invalid pending ttl"),
+        None => 30 * 24 * 60 * 60,
+    };
+    let db = db
+        .with_terminal_ttl(match terminal_ttl {
+            0 => None,
+            ttl => Some(Duration::from_secs(ttl)),
+        })
+        .with_pending_ttl(match pending_ttl {
+            0 => None,
+            ttl => Some(Duration::from_secs(ttl)),
+        });
+    let max_webhook_age = match matches.value_of("max-webhook-age") {
+        Some(age) => age.parse().expect("Invalid max webhook age"),
+        None => 3600,
+    };
+    let btcpay = matches.value_of("btcpay-url").map(|url| {
+        let client =
+            client::BtcPayClient::new(url, Duration::from_secs(10)).expect("Invalid BTCPay URL");
+        let client = match matches.value_of("btcpay-api-key") {
+            Some(api_key) => client.with_api_key(api_key),
+            None => client,
+        };
+        match matches.value_of("btcpay-store") {
+            Some(store_id) => client.with_default_store(store_id),
+            None => client,
+        }
+    });
+    let reconcile_interval: u64 = match matches.value_of("reconcile-interval") {
+        Some(interval) => interval.parse().expect("Invalid reconcile interval"),
+        None => 60,
+    };
+    let pending_timeout = match matches.value_of("pending-timeout") {
+        Some(timeout) => Duration::from_secs(timeout.parse().expect("Invalid pending timeout")),
+        None => Duration::from_secs(15 * 60),
+    };
+    let ping_interval = match matches.value_of("ping-interval") {
+        Some(interval) => interval.parse().expect("Invalid ping interval"),
+        None => 30,
+    };
+    let max_socket_lifetime = match matches.value_of("max-socket-lifetime") {
+        Some(lifetime) => lifetime.parse().expect("Invalid max socket lifetime"),
+        None => 24 * 60 * 60,
+    };
+    let hub = hub::Hub::new();
+
+    if fan_out {
+        task::spawn(db.clone().forward_notifications(hub.clone()));
+    }
+
+    let db = Arc::new(db);
+    if let Some(client) = btcpay.as_ref().filter(|client| client.has_api_key()) {
+        if reconcile_interval > 0 {
+            task::spawn(reconcile::run(
+                db.clone(),
+                hub.clone(),
+                client.clone(),
+                Duration::from_secs(reconcile_interval),
+            ));
+        }
+    }
+
+    if store_config.secrets_file.is_some() {
+        spawn_reload_on_sighup(store_config, stores.clone());
+    }
 
     let state: state::State<database::RedisDb> = state::State {
-        db: Arc::new(Mutex::new(database::RedisDb::new(
-            host.to_string(),
-            port.to_string(),
-            pass.to_string(),
-        ))),
-        hmac: hmac.to_string(),
+        db,
+        stores,
+        hub,
+        max_webhook_age: match max_webhook_age {
+            0 => None,
+            age => Some(Duration::from_secs(age)),
+        },
+        pending_timeout,
+        ping_interval: match ping_interval {
+            0 => None,
+            interval => Some(Duration::from_secs(interval)),
+        },
+        max_socket_lifetime: match max_socket_lifetime {
+            0 => None,
+            lifetime => Some(Duration::from_secs(lifetime)),
+        },
+        btcpay,
     };
 
     let mut app = tide::with_state(state);
 
     app.at("/btcpay").post(btcpay::handle_btcpay);
+    app.at("/btcpay/:store_id").post(btcpay::handle_btcpay);
+    app.at("/btcpay/ipn").post(btcpay::handle_ipn);
+    app.at("/btcpay/:store_id/ipn").post(btcpay::handle_ipn);
     app.at("/ws")
-        .with(WebSocket::new(websocket::websocket))
+        .with(WebSocket::new(websocket::websocket).with_protocols(protocol::PROTOCOLS))
         .get(|_| async move { Ok("not a websocket request") });
+    app.at("/sse").get(sse::sse);
+    app.at("/invoices/:invoice_id").get(lookup::get_invoice);
+    app.at("/invoices/:invoice_id/wait")
+        .get(lookup::wait_invoice);
+    app.at("/invoices/:invoice_id/history")
+        .get(lookup::get_history);
 
-    log::info!("Listening on {}:5000", host);
-    app.listen(format!("{}:5000", host)).await?;
+    // Not the Redis host, which may well be loopback or absent
+    let listen = matches.value_of("listen").unwrap_or("0.0.0.0:5000");
+    log::info!("Listening on {}", listen);
+    app.listen(listen).await?;
 
     Ok(())
 }
+
+/// Re-reads the secrets file whenever the process gets a SIGHUP, so secrets
+/// can be rotated without a restart.
+fn spawn_reload_on_sighup(config: store::StoreConfig, stores: store::SharedStores) {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP])
+        .expect("Failed to register SIGHUP handler");
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            match config.reload(&stores) {
+                Ok(()) => log::info!("Reloaded webhook secrets"),
+                Err(e) => log::error!("Keeping current webhook secrets, {}", e),
+            }
+        }
+    });
+}
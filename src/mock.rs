@@ -0,0 +1,218 @@
+//! Test doubles for Redis and the BTCPay API.
+
+use super::hub::Hub;
+use super::invoice::{
+    HistoryEntry, InvoiceCommands, InvoiceError, InvoiceKey, InvoiceStatus, InvoiceTerms,
+    InvoiceUpdate,
+};
+use super::state::State;
+use super::store::{Store, StoreRegistry, DEFAULT_STORE};
+use async_std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tide::convert::json;
+use tide::listener::Listener;
+
+#[derive(Clone, Debug)]
+pub struct MockDb {
+    invoices: Arc<Mutex<HashMap<InvoiceKey, InvoiceStatus>>>,
+    deliveries: Arc<Mutex<HashSet<String>>>,
+    seen: Arc<Mutex<HashSet<String>>>,
+    history: Arc<Mutex<HashMap<InvoiceKey, Vec<HistoryEntry>>>>,
+    terms: Arc<Mutex<HashMap<InvoiceKey, InvoiceTerms>>>,
+}
+
+impl MockDb {
+    pub fn new() -> MockDb {
+        MockDb {
+            invoices: Arc::new(Mutex::new(HashMap::new())),
+            deliveries: Arc::new(Mutex::new(HashSet::new())),
+            seen: Arc::new(Mutex::new(HashSet::new())),
+            history: Arc::new(Mutex::new(HashMap::new())),
+            terms: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+#[async_trait]
+impl InvoiceCommands for MockDb {
+    async fn get_invoice_status(&self, key: &InvoiceKey) -> Result<InvoiceStatus, InvoiceError> {
+        match self.invoices.lock().await.get(key) {
+            Some(invoice) => Ok(*invoice),
+            None => Err(InvoiceError::DoesNotExist),
+        }
+    }
+
+    async fn set_invoice_status(
+        &self,
+        update: &InvoiceUpdate,
+        delivery_id: Option<&str>,
+    ) -> Result<String, InvoiceError> {
+        let mut invoices = self.invoices.lock().await;
+        if let Some(delivery_id) = delivery_id {
+            if self.deliveries.lock().await.contains(delivery_id) {
+                return Err(InvoiceError::AlreadyExists);
+            }
+        }
+        if let Some(current) = invoices.get(&update.key) {
+            if !update
+                .status
+                .allowed_from(update.event.as_ref())
+                .contains(current)
+            {
+                return Err(InvoiceError::BadStatusUpdate);
+            }
+        }
+        invoices.insert(update.key.clone(), update.status);
+        // Still holding the invoices, as the script is atomic
+        self.append_invoice_history(update, delivery_id).await
+    }
+
+    async fn set_invoice_terms(
+        &self,
+        key: &InvoiceKey,
+        terms: &InvoiceTerms,
+    ) -> Result<(), InvoiceError> {
+        if self.invoices.lock().await.contains_key(key) {
+            let mut stored = self.terms.lock().await;
+            let stored = stored.entry(key.clone()).or_default();
+            stored.amount = terms.amount.clone().or(stored.amount.take());
+            stored.currency = terms.currency.clone().or(stored.currency.take());
+            stored.monitoring_ends = terms.monitoring_ends.or(stored.monitoring_ends);
+        }
+        Ok(())
+    }
+
+    async fn get_invoice_terms(&self, key: &InvoiceKey) -> Result<InvoiceTerms, InvoiceError> {
+        Ok(self
+            .terms
+            .lock()
+            .await
+            .get(key)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn claim_delivery(
+        &self,
+        delivery_id: &str,
+        _ttl: Duration,
+    ) -> Result<bool, InvoiceError> {
+        Ok(self.seen.lock().await.insert(delivery_id.to_string()))
+    }
+
+    async fn release_delivery(&self, delivery_id: &str) -> Result<(), InvoiceError> {
+        self.seen.lock().await.remove(delivery_id);
+        Ok(())
+    }
+
+    async fn pending_invoices(&self) -> Result<Vec<InvoiceKey>, InvoiceError> {
+        Ok(self
+            .invoices
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, status)| !status.is_terminal())
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    async fn append_invoice_history(
+        &self,
+        update: &InvoiceUpdate,
+        delivery_id: Option<&str>,
+    ) -> Result<String, InvoiceError> {
+        if let Some(delivery_id) = delivery_id {
+            if !self.deliveries.lock().await.insert(delivery_id.to_string()) {
+                return Err(InvoiceError::AlreadyExists);
+            }
+        }
+        let mut history = self.history.lock().await;
+        let entries = history.entry(update.key.clone()).or_default();
+        let received_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let id = format!("{}-{}", received_at, entries.len());
+        entries.push(HistoryEntry {
+            id: id.clone(),
+            received_at,
+            status: update.status,
+            delivery_id: delivery_id.map(str::to_string),
+            event: update.event.clone(),
+        });
+        Ok(id)
+    }
+
+    async fn get_invoice_history(
+        &self,
+        key: &InvoiceKey,
+    ) -> Result<Vec<HistoryEntry>, InvoiceError> {
+        Ok(self
+            .history
+            .lock()
+            .await
+            .get(key)
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+/// Applies `status` to an invoice of the default store as a webhook would,
+/// recording it in the history and publishing it. Returns its cursor.
+pub async fn publish(state: &State<MockDb>, invoice_id: &str, status: InvoiceStatus) -> String {
+    let mut update = InvoiceUpdate {
+        key: InvoiceKey::new(DEFAULT_STORE, invoice_id),
+        status,
+        event: None,
+        cursor: None,
+    };
+    let cursor = state.db.set_invoice_status(&update, None).await.unwrap();
+    update.cursor = Some(cursor.clone());
+    state.hub.publish(update);
+    cursor
+}
+
+/// State with the default store signing with `bob`, and `alice` mid rotation
+/// between `carol` and `dave`.
+pub fn mock_state() -> State<MockDb> {
+    let mut stores = StoreRegistry::new();
+    stores.insert(Store::new(DEFAULT_STORE, "bob"));
+    stores.insert(Store::new("alice", "carol"));
+    stores.insert("alice:next=dave".parse().unwrap());
+    // Already expired
+    stores.insert("alice:old=eve 1600000000".parse().unwrap());
+
+    State {
+        db: Arc::new(MockDb::new()),
+        stores: Arc::new(RwLock::new(stores)),
+        hub: Hub::new(),
+        max_webhook_age: None,
+        pending_timeout: Duration::from_secs(5),
+        ping_interval: None,
+        max_socket_lifetime: None,
+        btcpay: None,
+    }
+}
+
+/// Stands in for a BTCPay Server on a local port, answering GETs for each
+/// path in `responses` (without the leading slash) with its JSON value and
+/// anything else with a 404. Returns its base URL.
+pub async fn mock_btcpay(responses: serde_json::value::Value) -> String {
+    let mut btcpay = tide::with_state(responses);
+    btcpay
+        .at("/*path")
+        .get(|req: tide::Request<serde_json::value::Value>| async move {
+            Ok(match req.state().get(req.param("path")?) {
+                Some(body) => tide::Response::builder(200).body(json!(body)).build(),
+                None => tide::Response::new(404),
+            })
+        });
+
+    let mut listener = btcpay.bind("127.0.0.1:0").await.unwrap();
+    let url = listener.info()[0].connection().to_string();
+    async_std::task::spawn(async move { listener.accept().await });
+    url
+}
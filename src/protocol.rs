@@ -0,0 +1,233 @@
+//! The `btcpay-ws.v1` websocket protocol. Every frame is a JSON object
+//! tagged with its `type`:
+//!
+//! ```text
+//! server: {"type": "hello", "protocol": "btcpay-ws.v1"}
+//!         {"type": "pending", "storeId": "default", "invoiceId": "..."}
+//!         {"type": "status", "storeId": "default", "invoiceId": "...",
+//!          "status": "InvoiceSettled", "event": {"type": "InvoiceSettled", ...}}
+//!         {"type": "unsubscribed", "storeId": "default", "invoiceId": "..."}
+//!         {"type": "error", "code": "invoice_not_found", "message": "...",
+//!          "storeId": "default", "invoiceId": "..."}
+//!         {"type": "closing", "reason": "invoice_final"}
+//!         {"type": "pong"}
+//! client: {"type": "subscribe", "invoiceId": "...", "storeId": "...", "since": "..."}
+//!         {"type": "unsubscribe", "invoiceId": "...", "storeId": "..."}
+//!         {"type": "resubscribe", "invoiceId": "...", "storeId": "..."}
+//!         {"type": "ping"}
+//! ```
+//!
+//! `storeId` is optional in client messages and defaults to the default
+//! store. A subscription is answered with the invoice's current `status`, or
+//! `pending` if it has none yet, and ends by itself once the invoice is final.
+//! `resubscribe` starts over, answering with the current status again.
+//!
+//! `status` frames recorded in the invoice's history carry its id as
+//! `cursor`. A client reconnecting with `since` set to the last one it got
+//! has every change after it replayed before the live updates.
+//!
+//! The websocket close frame that follows `closing` carries the same reason,
+//! see `CloseReason::close_code`.
+//!
+//! Clients that don't ask for `btcpay-ws.v1` through `Sec-WebSocket-Protocol`
+//! get the original untyped frames instead, see `ServerMessage::to_legacy`.
+
+use super::invoice::{InvoiceKey, InvoiceUpdate};
+use super::store::DEFAULT_STORE;
+use serde::{Deserialize, Serialize};
+use tide::convert::json;
+
+pub const PROTOCOL_V1: &str = "btcpay-ws.v1";
+
+/// Every subprotocol this server speaks, preferred first.
+pub const PROTOCOLS: &[&str] = &[PROTOCOL_V1];
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ServerMessage {
+    /// First frame on every connection.
+    Hello {
+        protocol: &'static str,
+    },
+    /// The invoice has no status yet, a `status` follows once it does.
+    Pending(InvoiceKey),
+    Status(InvoiceUpdate),
+    /// The invoice is no longer watched, answering `unsubscribe`.
+    Unsubscribed(InvoiceKey),
+    Error {
+        code: ErrorCode,
+        message: String,
+        /// The invoice the error is about, if any.
+        #[serde(flatten, skip_serializing_if = "Option::is_none")]
+        invoice: Option<InvoiceKey>,
+    },
+    /// Last frame before the server closes the connection.
+    Closing {
+        reason: CloseReason,
+    },
+    Pong,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// No status showed up for the invoice in time.
+    InvoiceNotFound,
+    /// A client frame that isn't a valid `ClientMessage`.
+    BadMessage,
+    /// The invoice status could not be read, the client may reconnect.
+    Internal,
+    /// Subscribing to more invoices than one connection may watch.
+    TooManySubscriptions,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// The invoice is settled, expired or invalid, no more updates follow.
+    InvoiceFinal,
+    InvoiceNotFound,
+    Error,
+    /// The peer didn't answer a ping in time.
+    HeartbeatTimeout,
+    /// The connection has been open for as long as one may be.
+    MaxLifetime,
+    /// BTCPay no longer monitors the invoice, no more updates follow.
+    MonitoringEnded,
+}
+
+impl CloseReason {
+    /// The websocket close code sent along with the reason.
+    pub fn close_code(&self) -> u16 {
+        match self {
+            CloseReason::InvoiceFinal
+            | CloseReason::InvoiceNotFound
+            | CloseReason::MonitoringEnded => 1000,
+            CloseReason::Error => 1011,
+            // Going away, the client may reconnect
+            CloseReason::HeartbeatTimeout | CloseReason::MaxLifetime => 1001,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::InvoiceFinal => "invoice_final",
+            CloseReason::InvoiceNotFound => "invoice_not_found",
+            CloseReason::Error => "error",
+            CloseReason::HeartbeatTimeout => "heartbeat_timeout",
+            CloseReason::MaxLifetime => "max_lifetime",
+            CloseReason::MonitoringEnded => "monitoring_ended",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ClientMessage {
+    #[serde(rename_all = "camelCase")]
+    Subscribe {
+        #[serde(default)]
+        store_id: Option<String>,
+        invoice_id: String,
+        /// Where a reconnecting client left off, see `watcher::Since`.
+        #[serde(default)]
+        since: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Unsubscribe {
+        #[serde(default)]
+        store_id: Option<String>,
+        invoice_id: String,
+    },
+    /// Asks for the current status again, e.g. after missing frames.
+    #[serde(rename_all = "camelCase")]
+    Resubscribe {
+        #[serde(default)]
+        store_id: Option<String>,
+        invoice_id: String,
+    },
+    Ping,
+}
+
+/// The invoice named by a client message or query string.
+pub fn invoice_key(store_id: Option<&str>, invoice_id: &str) -> InvoiceKey {
+    InvoiceKey::new(store_id.unwrap_or(DEFAULT_STORE), invoice_id)
+}
+
+impl ServerMessage {
+    /// The frame sent to clients that didn't negotiate a protocol, if any.
+    pub fn to_legacy(&self) -> Option<serde_json::Value> {
+        match self {
+            ServerMessage::Pending(_) => Some(json!({"message": "pending"})),
+            ServerMessage::Status(update) => Some(json!({
+                "message": { "invoiceStatus": update.status, "event": update.event }
+            })),
+            ServerMessage::Error {
+                code: ErrorCode::InvoiceNotFound,
+                ..
+            } => Some(json!({"message": "status not found"})),
+            ServerMessage::Error { .. } => Some(json!({"message": "An error occured"})),
+            ServerMessage::Hello { .. }
+            | ServerMessage::Unsubscribed(_)
+            | ServerMessage::Closing { .. }
+            | ServerMessage::Pong => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::invoice::InvoiceStatus;
+
+    #[test]
+    fn test_server_message_frames() {
+        let update = InvoiceUpdate {
+            key: InvoiceKey::new("default", "bob"),
+            status: InvoiceStatus::Settled,
+            event: None,
+            cursor: None,
+        };
+        assert_eq!(
+            serde_json::to_value(ServerMessage::Status(update)).unwrap(),
+            json!({"type": "status", "storeId": "default", "invoiceId": "bob", "status": "InvoiceSettled"})
+        );
+        assert_eq!(
+            serde_json::to_value(ServerMessage::Error {
+                code: ErrorCode::InvoiceNotFound,
+                message: "no status for invoice".to_string(),
+                invoice: Some(InvoiceKey::new("default", "bob")),
+            })
+            .unwrap(),
+            json!({
+                "type": "error",
+                "code": "invoice_not_found",
+                "message": "no status for invoice",
+                "storeId": "default",
+                "invoiceId": "bob"
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(ServerMessage::Closing {
+                reason: CloseReason::InvoiceFinal
+            })
+            .unwrap(),
+            json!({"type": "closing", "reason": "invoice_final"})
+        );
+        assert_eq!(
+            serde_json::from_value::<ClientMessage>(json!({"type": "ping"})).unwrap(),
+            ClientMessage::Ping
+        );
+        assert_eq!(
+            serde_json::from_value::<ClientMessage>(
+                json!({"type": "subscribe", "invoiceId": "bob"})
+            )
+            .unwrap(),
+            ClientMessage::Subscribe {
+                store_id: None,
+                invoice_id: "bob".to_string(),
+                since: None,
+            }
+        );
+    }
+}
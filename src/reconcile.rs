@@ -0,0 +1,170 @@
+use super::client::{BtcPayClient, ClientError};
+use super::hub::Hub;
+use super::invoice::{InvoiceCommands, InvoiceError, InvoiceKey, InvoiceStatus, InvoiceUpdate};
+use async_std::sync::Arc;
+use async_std::task;
+use std::time::Duration;
+
+/// Brings an invoice in line with BTCPay's own view of it, publishing the
+/// change if there is one. Returns the status now stored.
+pub async fn refresh<T: InvoiceCommands>(
+    db: &T,
+    hub: &Hub,
+    client: &BtcPayClient,
+    key: &InvoiceKey,
+) -> Option<InvoiceStatus> {
+    let details = match client.get_invoice(key).await {
+        Ok(details) => details,
+        Err(ClientError::NotConfigured) => {
+            log::debug!("No BTCPay store id to look up invoice {}", key);
+            return None;
+        }
+        Err(e) => {
+            log::warn!("Failed to look up invoice {} on BTCPay, {:?}", key, e);
+            return None;
+        }
+    };
+
+    let stored = apply_status(db, hub, key, details.status).await;
+    // Kept for watchers, which don't look invoices up themselves
+    if db.set_invoice_terms(key, &details.terms).await.is_err() {
+        log::warn!("Failed to record terms of invoice {}", key);
+    }
+    stored
+}
+
+async fn apply_status<T: InvoiceCommands>(
+    db: &T,
+    hub: &Hub,
+    key: &InvoiceKey,
+    status: InvoiceStatus,
+) -> Option<InvoiceStatus> {
+    if let Ok(current) = db.get_invoice_status(key).await {
+        if current == status {
+            return Some(status);
+        }
+    }
+
+    let mut update = InvoiceUpdate {
+        key: key.clone(),
+        status,
+        event: None,
+        cursor: None,
+    };
+    match db.set_invoice_status(&update, None).await {
+        Ok(cursor) => {
+            log::info!("Reconciled invoice {} to {}", key, status);
+            update.cursor = Some(cursor);
+            hub.publish(update);
+            Some(status)
+        }
+        Err(InvoiceError::BadStatusUpdate) => {
+            log::warn!(
+                "BTCPay reports {} for invoice {}, an illegal status transition",
+                status,
+                key
+            );
+            db.get_invoice_status(key).await.ok()
+        }
+        Err(_) => None,
+    }
+}
+
+/// Re-checks every invoice not yet in a terminal status once.
+pub async fn reconcile_pending<T: InvoiceCommands>(db: &T, hub: &Hub, client: &BtcPayClient) {
+    let pending = match db.pending_invoices().await {
+        Ok(pending) => pending,
+        Err(_) => return,
+    };
+    log::debug!("Reconciling {} pending invoices", pending.len());
+    for key in pending {
+        refresh(db, hub, client, &key).await;
+    }
+}
+
+/// Reconciles pending invoices every `interval`, so an invoice whose
+/// webhook was lost does not stay stuck.
+pub async fn run<T: InvoiceCommands>(
+    db: Arc<T>,
+    hub: Hub,
+    client: BtcPayClient,
+    interval: Duration,
+) {
+    loop {
+        task::sleep(interval).await;
+        reconcile_pending(&*db, &hub, &client).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::invoice::InvoiceTerms;
+    use crate::mock::{mock_btcpay, MockDb};
+    use std::time::UNIX_EPOCH;
+    use tide::convert::json;
+
+    #[actix_rt::test]
+    async fn test_reconcile_pending() {
+        let url = mock_btcpay(json!({
+            "api/v1/stores/alice/invoices/bob": {"status": "Settled", "additionalStatus": "None"},
+            "api/v1/stores/BTCPAY_DEFAULT/invoices/bob": {
+                "status": "Processing",
+                "amount": "12.50",
+                "currency": "USD",
+                "monitoringExpiration": 1700000000
+            },
+        }))
+        .await;
+        let client = BtcPayClient::new(&url, Duration::from_secs(5))
+            .unwrap()
+            .with_api_key("key")
+            .with_default_store("BTCPAY_DEFAULT");
+        let db = MockDb::new();
+        let hub = Hub::new();
+
+        let alice = InvoiceKey::new("alice", "bob");
+        let lost = InvoiceKey::new("alice", "lost");
+        for key in [&alice, &lost] {
+            db.set_invoice_status(
+                &InvoiceUpdate {
+                    key: key.clone(),
+                    status: InvoiceStatus::Processing,
+                    event: None,
+                    cursor: None,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        let subscription = hub.subscribe(&alice);
+
+        reconcile_pending(&db, &hub, &client).await;
+
+        assert_eq!(
+            subscription.recv().await.map(|update| update.status),
+            Some(InvoiceStatus::Settled)
+        );
+        assert_eq!(db.pending_invoices().await.unwrap(), vec![lost]);
+
+        // Unknown so far, as when a websocket asks for it first
+        let default = InvoiceKey::new(crate::store::DEFAULT_STORE, "bob");
+        assert_eq!(
+            refresh(&db, &hub, &client, &default).await,
+            Some(InvoiceStatus::Processing)
+        );
+        assert_eq!(
+            db.get_invoice_status(&default).await.unwrap(),
+            InvoiceStatus::Processing
+        );
+        assert_eq!(
+            db.get_invoice_terms(&default).await.unwrap(),
+            InvoiceTerms {
+                amount: Some("12.50".to_string()),
+                currency: Some("USD".to_string()),
+                monitoring_ends: Some(UNIX_EPOCH + Duration::from_secs(1700000000)),
+            }
+        );
+    }
+}
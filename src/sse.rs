@@ -0,0 +1,158 @@
+use super::invoice::InvoiceCommands;
+use super::protocol::{invoice_key, ServerMessage};
+use super::state::State;
+use super::watcher::{Outbox, Since, Watcher};
+use async_std::future;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::time::Instant;
+use tide::sse::Sender;
+
+#[derive(Deserialize)]
+struct InvoiceQuery {
+    store_id: Option<String>,
+    invoice_id: String,
+}
+
+/// An event stream. Each event is named after the frame's `type` and
+/// carries the `btcpay-ws.v1` frame as its data. `status` events have the
+/// frame's `cursor` as their id, or the status when there is none.
+struct EventStream {
+    sender: Sender,
+}
+
+#[async_trait]
+impl Outbox for EventStream {
+    async fn send(&self, message: &ServerMessage) -> tide::Result<()> {
+        let data = serde_json::to_value(message)?;
+        let name = data["type"].as_str().unwrap_or("message");
+        // Comes back as Last-Event-ID when the browser reconnects
+        let id = match message {
+            ServerMessage::Status(update) => Some(
+                update
+                    .cursor
+                    .clone()
+                    .unwrap_or_else(|| update.status.to_string()),
+            ),
+            _ => None,
+        };
+        self.sender
+            .send(name, data.to_string(), id.as_deref())
+            .await?;
+        Ok(())
+    }
+}
+
+/// Streams an invoice's status changes as Server-Sent Events, for clients
+/// whose proxies break websockets. Behaves as `/ws?invoice_id=`, with
+/// `Last-Event-ID` standing in for `since`.
+pub async fn sse<T: InvoiceCommands + std::clone::Clone + 'static>(
+    req: tide::Request<State<T>>,
+) -> tide::Result {
+    // Checked before the stream starts, to still answer 400
+    req.query::<InvoiceQuery>()?;
+    Ok(tide::sse::upgrade(req, stream))
+}
+
+async fn stream<T: InvoiceCommands + std::clone::Clone>(
+    req: tide::Request<State<T>>,
+    sender: Sender,
+) -> tide::Result<()> {
+    let query = req.query::<InvoiceQuery>()?;
+    let since = req
+        .header("Last-Event-ID")
+        .map(|id| Since::parse(id.as_str()));
+    let mut watcher = Watcher::new(req.state(), EventStream { sender }, true);
+
+    watcher
+        .subscribe_since(
+            invoice_key(query.store_id.as_deref(), &query.invoice_id),
+            since,
+        )
+        .await?;
+
+    while watcher.close_reason.is_none() {
+        let update = match watcher.next_deadline() {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                future::timeout(remaining, watcher.recv()).await.ok()
+            }
+            None => Some(watcher.recv().await),
+        };
+
+        match update {
+            Some(Some(update)) => watcher.on_update(update).await?,
+            Some(None) => break,
+            None => watcher.on_timer().await?,
+        }
+    }
+
+    if let Some(reason) = watcher.close_reason {
+        watcher
+            .outbox
+            .send(&ServerMessage::Closing { reason })
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::invoice::InvoiceStatus;
+    use crate::mock::{mock_state, publish, MockDb};
+    use std::time::Duration;
+    use tide_testing::TideTestingExt;
+
+    fn app(state: State<MockDb>) -> tide::Server<State<MockDb>> {
+        let mut app = tide::with_state(state);
+        app.at("/sse").get(sse);
+        app
+    }
+
+    #[actix_rt::test]
+    async fn test_sse() {
+        let state = mock_state();
+        let app = app(state.clone());
+
+        let response = app.get("/sse").await.unwrap();
+        assert_eq!(response.status(), 400);
+
+        let publishing = async_std::task::spawn(async move {
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            let created = publish(&state, "bob", InvoiceStatus::Created).await;
+            let settled = publish(&state, "bob", InvoiceStatus::Settled).await;
+            (created, settled)
+        });
+        let body = app.get("/sse?invoice_id=bob").recv_string().await.unwrap();
+        let (created, settled) = publishing.await;
+        assert_eq!(
+            body,
+            [
+                "event:pending\n".to_string(),
+                "data:{\"invoiceId\":\"bob\",\"storeId\":\"default\",\"type\":\"pending\"}\n\n".to_string(),
+                "event:status\n".to_string(),
+                format!("id:{}\n", created),
+                format!("data:{{\"cursor\":\"{}\",\"invoiceId\":\"bob\",\"status\":\"InvoiceCreated\",\"storeId\":\"default\",\"type\":\"status\"}}\n\n", created),
+                "event:status\n".to_string(),
+                format!("id:{}\n", settled),
+                format!("data:{{\"cursor\":\"{}\",\"invoiceId\":\"bob\",\"status\":\"InvoiceSettled\",\"storeId\":\"default\",\"type\":\"status\"}}\n\n", settled),
+                "event:closing\n".to_string(),
+                "data:{\"reason\":\"invoice_final\",\"type\":\"closing\"}\n\n".to_string(),
+            ]
+            .concat()
+        );
+
+        // Resuming after the last status only closes
+        let body = app
+            .get("/sse?invoice_id=bob")
+            .header("Last-Event-ID", "InvoiceSettled")
+            .recv_string()
+            .await
+            .unwrap();
+        assert_eq!(
+            body,
+            "event:closing\ndata:{\"reason\":\"invoice_final\",\"type\":\"closing\"}\n\n"
+        );
+    }
+}
@@ -1,38 +1,133 @@
+use super::client::BtcPayClient;
+use super::hub::Hub;
 use super::invoice::InvoiceCommands;
-use async_std::sync::{Arc, Mutex};
+use super::store::{SharedStores, Store};
+use async_std::sync::Arc;
 use hmac::{Hmac, Mac, NewMac};
 use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Why a correctly signed webhook is still refused as a possible replay.
+#[derive(Debug, PartialEq)]
+pub enum ReplayError {
+    MissingTimestamp,
+    Stale,
+    AlreadySeen,
+}
+
+/// Why a webhook's `BTCPAY-SIG` header was refused.
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+    /// No `BTCPAY-SIG` header.
+    Missing,
+    /// Not of the form `sha256=HEX`, or not a SHA-256 digest.
+    Malformed,
+    /// Signed with something other than `sha256`.
+    UnsupportedAlgorithm,
+    /// Well formed, but not signed by any of the store's active secrets.
+    Mismatch,
+}
+
+impl SignatureError {
+    pub fn status(&self) -> u16 {
+        match self {
+            SignatureError::Missing => 400,
+            _ => 401,
+        }
+    }
+
+    pub fn detail(&self) -> &'static str {
+        match self {
+            SignatureError::Missing => "missing BTCPAY-SIG header",
+            SignatureError::Malformed => "invalid BTCPAY-SIG",
+            SignatureError::UnsupportedAlgorithm => "invalid hmac operation",
+            SignatureError::Mismatch => "invalid hmac",
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+impl std::fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.detail())
+    }
+}
 
 #[derive(Clone)]
 pub struct State<T: InvoiceCommands + std::clone::Clone> {
-    pub db: Arc<Mutex<T>>,
-    pub hmac: String,
+    pub db: Arc<T>,
+    pub stores: SharedStores,
+    pub hub: Hub,
+    /// How far a webhook's timestamp may be from now, `None` accepts any.
+    pub max_webhook_age: Option<Duration>,
+    /// How long a websocket waits for the first status of an unknown invoice.
+    pub pending_timeout: Duration,
+    /// How often websockets are pinged, a peer that hasn't answered the
+    /// previous ping by the next one is dropped.
+    pub ping_interval: Option<Duration>,
+    /// How long a websocket may stay open.
+    pub max_socket_lifetime: Option<Duration>,
+    /// BTCPay Server to check legacy IPNs against.
+    pub btcpay: Option<BtcPayClient>,
 }
 
 impl<T: InvoiceCommands + std::clone::Clone> State<T> {
-    pub fn verify_hmac(&self, data: String, sig: String) -> bool {
-        let mut mac = HmacSha25::new_varkey(self.hmac.as_bytes()).expect("HMAC key error");
-        mac.update(data.as_bytes());
-
-        let decoded_message: Vec<u8> = match hex::decode(sig) {
-            Ok(msg) => msg,
-            Err(e) => {
-                log::warn!("{}", e);
-                return false;
-            }
-        };
+    /// The store a webhook belongs to, see `StoreRegistry::select`.
+    pub fn select_store(&self, route: Option<&str>, payload: Option<&str>) -> Option<Store> {
+        self.stores.read().unwrap().select(route, payload).cloned()
+    }
+
+    /// Checks a `BTCPAY-SIG` header against the raw request body. Only the
+    /// outcome is logged, never the signature or anything computed from a
+    /// secret.
+    pub fn verify_signature(
+        &self,
+        store: &Store,
+        body: &[u8],
+        header: Option<&str>,
+    ) -> Result<(), SignatureError> {
+        let header = header.ok_or(SignatureError::Missing)?;
+        let (algorithm, digest) = header.split_once('=').ok_or(SignatureError::Malformed)?;
+        if algorithm != "sha256" {
+            return Err(SignatureError::UnsupportedAlgorithm);
+        }
 
-        log::trace!("{:?}", decoded_message);
+        let mut signature = [0u8; 32];
+        hex::decode_to_slice(digest, &mut signature).map_err(|_| SignatureError::Malformed)?;
 
-        log::trace!("{}", hex::encode(mac.clone().finalize().into_bytes()));
+        for (name, secret) in store.active_secrets() {
+            let mut mac = HmacSha25::new_varkey(secret.value.as_bytes()).expect("HMAC key error");
+            mac.update(body);
 
-        match mac.verify(&decoded_message.as_slice()) {
-            Ok(()) => true,
-            Err(e) => {
-                log::warn!("{}", e);
-                false
+            // Constant time comparison
+            if mac.verify(&signature).is_ok() {
+                log::debug!("Webhook for store {} matched secret {}", store.id, name);
+                return Ok(());
             }
         }
+
+        log::warn!("Webhook for store {} matched no active secret", store.id);
+        Err(SignatureError::Mismatch)
+    }
+
+    /// Checks a webhook's timestamp against the freshness window. Timestamps
+    /// ahead of our clock get the same allowance, for skew.
+    pub fn check_timestamp(&self, timestamp: Option<i64>) -> Result<(), ReplayError> {
+        let max_age = match self.max_webhook_age {
+            Some(max_age) => max_age,
+            None => return Ok(()),
+        };
+        let timestamp = timestamp.ok_or(ReplayError::MissingTimestamp)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+
+        if (now - timestamp).unsigned_abs() > max_age.as_secs() {
+            return Err(ReplayError::Stale);
+        }
+        Ok(())
     }
 }
 
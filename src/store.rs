@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Store used for the `--hmac` flag and requests that don't name a store.
+/// Its invoices were kept under bare Redis keys before `migrate`.
+pub const DEFAULT_STORE: &str = "default";
+
+/// A webhook secret. Several can be active for a store at once so the secret
+/// can be rotated in BTCPay without rejecting webhooks in between.
+#[derive(Clone, Debug)]
+pub struct Secret {
+    pub label: Option<String>,
+    pub value: String,
+    pub expires: Option<SystemTime>,
+}
+
+impl Secret {
+    pub fn new(value: &str) -> Secret {
+        Secret {
+            label: None,
+            value: value.to_string(),
+            expires: None,
+        }
+    }
+
+    pub fn is_active(&self, now: SystemTime) -> bool {
+        self.expires.is_none_or(|expires| now < expires)
+    }
+}
+
+/// A BTCPay store delivering webhooks to this service.
+#[derive(Clone, Debug)]
+pub struct Store {
+    pub id: String,
+    pub secrets: Vec<Secret>,
+}
+
+impl Store {
+    pub fn new(id: &str, secret: &str) -> Store {
+        Store {
+            id: id.to_string(),
+            secrets: vec![Secret::new(secret)],
+        }
+    }
+
+    /// Secrets webhooks may currently be signed with, along with a name for
+    /// logging: the label, or the position when there is none.
+    pub fn active_secrets(&self) -> impl Iterator<Item = (String, &Secret)> {
+        let now = SystemTime::now();
+        self.secrets
+            .iter()
+            .enumerate()
+            .filter(move |(_, secret)| secret.is_active(now))
+            .map(|(i, secret)| {
+                let name = match &secret.label {
+                    Some(label) => label.clone(),
+                    None => format!("#{}", i + 1),
+                };
+                (name, secret)
+            })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StoreRegistry {
+    stores: HashMap<String, Store>,
+}
+
+impl StoreRegistry {
+    pub fn new() -> StoreRegistry {
+        StoreRegistry::default()
+    }
+
+    /// Adds a store, or its secrets to the ones already registered for it.
+    pub fn insert(&mut self, store: Store) {
+        match self.stores.get_mut(&store.id) {
+            Some(existing) => existing.secrets.extend(store.secrets),
+            None => {
+                self.stores.insert(store.id.clone(), store);
+            }
+        }
+    }
+
+    pub fn get(&self, store_id: &str) -> Option<&Store> {
+        self.stores.get(store_id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stores.is_empty()
+    }
+
+    /// Picks the store a webhook belongs to: the one named in the route,
+    /// else the `storeId` from the payload, else the default store.
+    pub fn select(&self, route: Option<&str>, payload: Option<&str>) -> Option<&Store> {
+        match route {
+            Some(store_id) => self.get(store_id),
+            None => payload
+                .and_then(|store_id| self.get(store_id))
+                .or_else(|| self.get(DEFAULT_STORE)),
+        }
+    }
+}
+
+/// Registry shared with the request handlers, swapped out on reload.
+pub type SharedStores = Arc<RwLock<StoreRegistry>>;
+
+/// Stores from `--hmac`/`--store`, merged with those from `--secrets-file`.
+#[derive(Clone, Debug)]
+pub struct StoreConfig {
+    pub stores: StoreRegistry,
+    pub secrets_file: Option<String>,
+}
+
+impl StoreConfig {
+    pub fn load(&self) -> Result<StoreRegistry, String> {
+        let mut registry = self.stores.clone();
+        if let Some(path) = &self.secrets_file {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("failed to read {}: {}", path, e))?;
+            for store in parse_secrets(&contents)? {
+                registry.insert(store);
+            }
+        }
+        Ok(registry)
+    }
+
+    /// Re-reads the secrets file into `shared`, keeping the current secrets
+    /// if it can't be loaded.
+    pub fn reload(&self, shared: &SharedStores) -> Result<(), String> {
+        let registry = self.load()?;
+        *shared.write().unwrap() = registry;
+        Ok(())
+    }
+}
+
+/// Parses a secrets file: one `--store` value per line, blank lines and
+/// lines starting with `#` are skipped.
+pub fn parse_secrets(contents: &str) -> Result<Vec<Store>, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| line.parse().map_err(|e| format!("line {}: {}", i + 1, e)))
+        .collect()
+}
+
+impl std::str::FromStr for Store {
+    type Err = String;
+
+    /// Parses the `STORE_ID[:LABEL]=SECRET [EXPIRES]` form taken by `--store`,
+    /// where `EXPIRES` is a unix timestamp in seconds.
+    fn from_str(value: &str) -> Result<Store, String> {
+        let invalid = || {
+            format!(
+                "expected STORE_ID[:LABEL]=SECRET [EXPIRES], got '{}'",
+                value
+            )
+        };
+
+        let mut parts = value.split_whitespace();
+        let (name, secret) = parts
+            .next()
+            .and_then(|part| part.split_once('='))
+            .ok_or_else(invalid)?;
+        let expires = match parts.next() {
+            Some(expires) => {
+                Some(UNIX_EPOCH + Duration::from_secs(expires.parse().map_err(|_| invalid())?))
+            }
+            None => None,
+        };
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        let (id, label) = match name.split_once(':') {
+            Some((id, label)) if !label.is_empty() => (id, Some(label.to_string())),
+            Some(_) => return Err(invalid()),
+            None => (name, None),
+        };
+        if id.is_empty() || secret.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Store {
+            id: id.to_string(),
+            secrets: vec![Secret {
+                label,
+                value: secret.to_string(),
+                expires,
+            }],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_secrets() {
+        let stores = parse_secrets(
+            "# rotating shop\n\nshop:old=alpha 1600000000\nshop:new=beta\ndonations=gamma\n",
+        )
+        .unwrap();
+
+        let mut registry = StoreRegistry::new();
+        for store in stores {
+            registry.insert(store);
+        }
+
+        let shop = registry.get("shop").unwrap();
+        assert_eq!(shop.secrets.len(), 2);
+        let active: Vec<String> = shop.active_secrets().map(|(name, _)| name).collect();
+        assert_eq!(active, vec!["new".to_string()]);
+
+        let donations = registry.get("donations").unwrap();
+        let active: Vec<String> = donations.active_secrets().map(|(name, _)| name).collect();
+        assert_eq!(active, vec!["#1".to_string()]);
+
+        let err = parse_secrets("shop=alpha\nshop:=beta\n").unwrap_err();
+        assert!(err.starts_with("line 2:"), "{}", err);
+    }
+}
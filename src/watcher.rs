@@ -0,0 +1,342 @@
+use super::hub::Subscription;
+use super::invoice::{
+    HistoryEntry, InvoiceCommands, InvoiceError, InvoiceKey, InvoiceStatus, InvoiceUpdate,
+};
+use super::protocol::{CloseReason, ErrorCode, ServerMessage};
+use super::reconcile;
+use super::state::State;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::time::{Instant, SystemTime};
+
+/// Most invoices one connection may watch at once.
+const MAX_SUBSCRIPTIONS: usize = 256;
+
+/// Where a `Watcher` sends its frames, a websocket or an event stream.
+#[async_trait]
+pub trait Outbox: Send + Sync {
+    async fn send(&self, message: &ServerMessage) -> tide::Result<()>;
+}
+
+/// Where a reconnecting client left off with an invoice.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Since {
+    /// The status it last got, which isn't sent again.
+    Status(InvoiceStatus),
+    /// The `cursor` of the last status it got, every change recorded after
+    /// it is replayed.
+    Cursor(String),
+}
+
+impl Since {
+    /// A status name, or else a cursor.
+    pub fn parse(since: &str) -> Since {
+        match since.parse() {
+            Ok(status) => Since::Status(status),
+            Err(_) => Since::Cursor(since.to_string()),
+        }
+    }
+}
+
+/// What a connection knows about an invoice it watches.
+struct Watch {
+    status: Option<InvoiceStatus>,
+    /// When to give up on an invoice that has no status yet.
+    pending_until: Option<Instant>,
+    /// When BTCPay stops monitoring the invoice, no update is expected after.
+    monitored_until: Option<Instant>,
+}
+
+/// The invoices one client connection watches, whatever the transport.
+/// Turns hub updates, pending timeouts and subscription requests into
+/// `ServerMessage`s for its `Outbox`.
+pub struct Watcher<'a, T: InvoiceCommands + std::clone::Clone, O: Outbox> {
+    state: &'a State<T>,
+    pub outbox: O,
+    subscription: Subscription,
+    watches: HashMap<InvoiceKey, Watch>,
+    /// Opened for one invoice, the connection is closed once that invoice is
+    /// done.
+    single: bool,
+    /// Set once the connection should be closed, and why.
+    pub close_reason: Option<CloseReason>,
+    /// When the connection is closed regardless, see `State::max_socket_lifetime`.
+    /// Connections watching invoices BTCPay has deadlines for end sooner.
+    closes_at: Option<Instant>,
+}
+
+impl<'a, T: InvoiceCommands + std::clone::Clone, O: Outbox> Watcher<'a, T, O> {
+    pub fn new(state: &'a State<T>, outbox: O, single: bool) -> Self {
+        Watcher {
+            state,
+            outbox,
+            subscription: state.hub.subscription(),
+            watches: HashMap::new(),
+            single,
+            close_reason: None,
+            closes_at: state
+                .max_socket_lifetime
+                .map(|lifetime| Instant::now() + lifetime),
+        }
+    }
+
+    pub async fn subscribe(&mut self, key: InvoiceKey) -> tide::Result<()> {
+        self.subscribe_since(key, None).await
+    }
+
+    /// Watches `key`, first catching the client up from `since`: replaying
+    /// the changes after its cursor, then sending the current status unless
+    /// the client already has it. A cursor no longer in the history only
+    /// gets the current status.
+    pub async fn subscribe_since(
+        &mut self,
+        key: InvoiceKey,
+        since: Option<Since>,
+    ) -> tide::Result<()> {
+        if self.watches.contains_key(&key) {
+            return Ok(());
+        }
+        if self.watches.len() >= MAX_SUBSCRIPTIONS {
+            return self
+                .outbox
+                .send(&ServerMessage::Error {
+                    code: ErrorCode::TooManySubscriptions,
+                    message: format!("at most {} invoices per connection", MAX_SUBSCRIPTIONS),
+                    invoice: Some(key),
+                })
+                .await;
+        }
+
+        // Subscribe before reading the current status so an update landing in
+        // between is not lost.
+        self.subscription.add(&key);
+        let state = self.state;
+
+        match state.db.get_invoice_status(&key).await {
+            Ok(status) => {
+                let monitored_until = if status.is_terminal() {
+                    None
+                } else {
+                    self.monitoring_deadline(&key).await
+                };
+                let history = self.history(&key).await;
+                let mut seen = match &since {
+                    Some(Since::Status(seen)) => Some(*seen),
+                    _ => None,
+                };
+                if let Some(Since::Cursor(cursor)) = &since {
+                    if let Some(position) = history.iter().position(|entry| &entry.id == cursor) {
+                        for entry in &history[position + 1..] {
+                            self.outbox
+                                .send(&ServerMessage::Status(entry.to_update(&key)))
+                                .await?;
+                        }
+                        seen = history.last().map(|entry| entry.status);
+                    }
+                }
+
+                self.watches.insert(
+                    key.clone(),
+                    Watch {
+                        status: Some(status),
+                        pending_until: None,
+                        monitored_until,
+                    },
+                );
+                if seen != Some(status) {
+                    // Pointing at the history when it is up to date
+                    let update = match history.last() {
+                        Some(entry) if entry.status == status => entry.to_update(&key),
+                        _ => InvoiceUpdate {
+                            key: key.clone(),
+                            status,
+                            event: None,
+                            cursor: None,
+                        },
+                    };
+                    self.outbox.send(&ServerMessage::Status(update)).await?;
+                }
+                if status.is_terminal() {
+                    self.finish(&key, CloseReason::InvoiceFinal);
+                }
+            }
+            Err(InvoiceError::DoesNotExist) => {
+                // Not seen yet, perhaps its webhook was lost. What BTCPay
+                // reports is published to the subscription above.
+                let found = match &state.btcpay {
+                    Some(client) if client.has_api_key() => {
+                        reconcile::refresh(&*state.db, &state.hub, client, &key)
+                            .await
+                            .is_some()
+                    }
+                    _ => false,
+                };
+                // Or the page opened before InvoiceCreated arrived
+                if !found {
+                    self.outbox
+                        .send(&ServerMessage::Pending(key.clone()))
+                        .await?;
+                }
+                self.watches.insert(
+                    key,
+                    Watch {
+                        status: None,
+                        pending_until: Some(Instant::now() + state.pending_timeout),
+                        monitored_until: None,
+                    },
+                );
+            }
+            Err(e) => {
+                log::error!("Failed to read status of invoice {}, {}", key, e);
+                self.outbox
+                    .send(&ServerMessage::Error {
+                        code: ErrorCode::Internal,
+                        message: "failed to read invoice status".to_string(),
+                        invoice: Some(key.clone()),
+                    })
+                    .await?;
+                self.finish(&key, CloseReason::Error);
+            }
+        }
+        Ok(())
+    }
+
+    /// The invoice's history, empty if it can't be read.
+    async fn history(&self, key: &InvoiceKey) -> Vec<HistoryEntry> {
+        match self.state.db.get_invoice_history(key).await {
+            Ok(history) => history,
+            Err(e) => {
+                log::warn!("Failed to read history of invoice {}, {}", key, e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// When BTCPay stops monitoring the invoice, as recorded when it was last
+    /// reconciled, never looked up here. Now when already past, so a client
+    /// reconnecting afterwards is let go at once. `None` when unknown, leaving
+    /// the connection's own lifetime.
+    async fn monitoring_deadline(&self, key: &InvoiceKey) -> Option<Instant> {
+        let ends = match self.state.db.get_invoice_terms(key).await {
+            Ok(terms) => terms.monitoring_ends?,
+            Err(e) => {
+                log::warn!("Failed to read terms of invoice {}, {}", key, e);
+                return None;
+            }
+        };
+        let remaining = ends.duration_since(SystemTime::now()).unwrap_or_default();
+        Some(Instant::now() + remaining)
+    }
+
+    /// Watches `key` afresh, sending its current status even if unchanged.
+    pub async fn resubscribe(&mut self, key: InvoiceKey) -> tide::Result<()> {
+        self.subscription.remove(&key);
+        self.watches.remove(&key);
+        self.subscribe(key).await
+    }
+
+    pub async fn unsubscribe(&mut self, key: InvoiceKey) -> tide::Result<()> {
+        self.subscription.remove(&key);
+        self.watches.remove(&key);
+        self.outbox.send(&ServerMessage::Unsubscribed(key)).await
+    }
+
+    /// Stops watching an invoice that is done, a single invoice connection
+    /// is closed with `reason`.
+    fn finish(&mut self, key: &InvoiceKey, reason: CloseReason) {
+        self.subscription.remove(key);
+        self.watches.remove(key);
+        if self.single && self.watches.is_empty() {
+            self.close_reason = Some(reason);
+        }
+    }
+
+    /// The next update for any watched invoice, `None` once the hub is gone.
+    pub async fn recv(&self) -> Option<InvoiceUpdate> {
+        self.subscription.recv().await
+    }
+
+    pub async fn on_update(&mut self, update: InvoiceUpdate) -> tide::Result<()> {
+        let watch = match self.watches.get_mut(&update.key) {
+            Some(watch) => watch,
+            // Queued before an unsubscribe
+            None => return Ok(()),
+        };
+        let status = update.status;
+        if Some(status) == watch.status {
+            return Ok(());
+        }
+        // Until known, the invoice may have been reconciled since
+        let unmonitored = watch.monitored_until.is_none();
+        watch.status = Some(status);
+        watch.pending_until = None;
+
+        let key = update.key.clone();
+        log::trace!("sending status");
+        self.outbox.send(&ServerMessage::Status(update)).await?;
+
+        if status.is_terminal() {
+            self.finish(&key, CloseReason::InvoiceFinal);
+        } else if unmonitored {
+            let monitored_until = self.monitoring_deadline(&key).await;
+            if let Some(watch) = self.watches.get_mut(&key) {
+                watch.monitored_until = monitored_until;
+            }
+        }
+        Ok(())
+    }
+
+    /// The soonest the lifetime, an invoice with no status yet or the end of
+    /// an invoice's monitoring is due.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.watches
+            .values()
+            .flat_map(|watch| watch.pending_until.into_iter().chain(watch.monitored_until))
+            .chain(self.closes_at)
+            .min()
+    }
+
+    /// Closes the connection past its lifetime, gives up on invoices that
+    /// had no status in time and stops watching those BTCPay no longer
+    /// monitors.
+    pub async fn on_timer(&mut self) -> tide::Result<()> {
+        let now = Instant::now();
+        if self.closes_at.is_some_and(|at| at <= now) {
+            self.close_reason = Some(CloseReason::MaxLifetime);
+            return Ok(());
+        }
+
+        let expired: Vec<InvoiceKey> = self
+            .watches
+            .iter()
+            .filter(|(_, watch)| watch.pending_until.is_some_and(|until| until <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in expired {
+            self.outbox
+                .send(&ServerMessage::Error {
+                    code: ErrorCode::InvoiceNotFound,
+                    message: format!("no status for invoice {}", key),
+                    invoice: Some(key.clone()),
+                })
+                .await?;
+            self.finish(&key, CloseReason::InvoiceNotFound);
+        }
+
+        let unmonitored: Vec<InvoiceKey> = self
+            .watches
+            .iter()
+            .filter(|(_, watch)| watch.monitored_until.is_some_and(|until| until <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in unmonitored {
+            self.outbox
+                .send(&ServerMessage::Unsubscribed(key.clone()))
+                .await?;
+            self.finish(&key, CloseReason::MonitoringEnded);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use super::invoice::InvoiceStatus;
+use serde::{Deserialize, Serialize};
+
+/// A BTCPay Greenfield webhook delivery: the delivery metadata plus the
+/// invoice event it carries.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Webhook {
+    #[serde(default)]
+    pub delivery_id: Option<String>,
+    #[serde(default)]
+    pub webhook_id: Option<String>,
+    #[serde(default)]
+    pub original_delivery_id: Option<String>,
+    #[serde(default)]
+    pub is_redelivery: bool,
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+    #[serde(default)]
+    pub store_id: Option<String>,
+    pub invoice_id: String,
+    #[serde(flatten)]
+    pub event: WebhookEvent,
+}
+
+/// Every invoice event BTCPay Greenfield sends, keyed on the `type` field.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum WebhookEvent {
+    InvoiceCreated,
+    #[serde(rename_all = "camelCase")]
+    InvoiceReceivedPayment {
+        #[serde(default)]
+        after_expiration: bool,
+        #[serde(default)]
+        payment_method: Option<String>,
+        #[serde(default)]
+        payment: Option<Payment>,
+    },
+    #[serde(rename_all = "camelCase")]
+    InvoiceProcessing {
+        #[serde(default)]
+        over_paid: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    InvoiceSettled {
+        #[serde(default)]
+        manually_marked: bool,
+        #[serde(default)]
+        over_paid: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    InvoiceExpired {
+        #[serde(default)]
+        partially_paid: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    InvoiceInvalid {
+        #[serde(default)]
+        manually_marked: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    InvoicePaymentSettled {
+        #[serde(default)]
+        after_expiration: bool,
+        #[serde(default)]
+        payment_method: Option<String>,
+        #[serde(default)]
+        payment: Option<Payment>,
+    },
+    /// Any event type this service does not track.
+    #[serde(other)]
+    Unsupported,
+}
+
+/// A single payment towards an invoice, as sent with payment events.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Payment {
+    pub id: String,
+    #[serde(default)]
+    pub received_date: Option<i64>,
+    #[serde(default)]
+    pub value: Option<String>,
+    #[serde(default)]
+    pub fee: Option<String>,
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default)]
+    pub destination: Option<String>,
+}
+
+impl WebhookEvent {
+    /// The invoice status this event moves the invoice to.
+    pub fn status(&self) -> Option<InvoiceStatus> {
+        match self {
+            WebhookEvent::InvoiceCreated => Some(InvoiceStatus::Created),
+            WebhookEvent::InvoiceReceivedPayment { .. } => Some(InvoiceStatus::ReceivedPayment),
+            WebhookEvent::InvoiceProcessing { .. } => Some(InvoiceStatus::Processing),
+            WebhookEvent::InvoiceSettled { .. } => Some(InvoiceStatus::Settled),
+            WebhookEvent::InvoiceExpired { .. } => Some(InvoiceStatus::Expired),
+            WebhookEvent::InvoiceInvalid { .. } => Some(InvoiceStatus::Invalid),
+            WebhookEvent::InvoicePaymentSettled { .. } => Some(InvoiceStatus::PaymentSettled),
+            WebhookEvent::Unsupported => None,
+        }
+    }
+}
+
+/// An invoice in the legacy Bitpay-compatible format, as POSTed by IPN and
+/// returned from the legacy `/invoices/{id}` API.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LegacyInvoice {
+    pub id: String,
+    pub status: String,
+    /// `false`, or a string such as `paidPartial` or `paidOver`.
+    #[serde(default)]
+    pub exception_status: serde_json::Value,
+}
+
+impl LegacyInvoice {
+    /// The Greenfield status matching this legacy status.
+    pub fn status(&self) -> Option<InvoiceStatus> {
+        match self.status.as_str() {
+            "new" if self.exception_status == "paidPartial" => Some(InvoiceStatus::ReceivedPayment),
+            "new" => Some(InvoiceStatus::Created),
+            "paid" => Some(InvoiceStatus::Processing),
+            "confirmed" | "complete" => Some(InvoiceStatus::Settled),
+            "expired" => Some(InvoiceStatus::Expired),
+            "invalid" => Some(InvoiceStatus::Invalid),
+            _ => None,
+        }
+    }
+}
+
+/// A legacy IPN body, the invoice itself or, with extended notifications
+/// enabled, wrapped with the event that triggered it.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum IpnPayload {
+    Extended { data: LegacyInvoice },
+    Plain(LegacyInvoice),
+}
+
+impl IpnPayload {
+    pub fn invoice(&self) -> &LegacyInvoice {
+        match self {
+            IpnPayload::Extended { data } => data,
+            IpnPayload::Plain(invoice) => invoice,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tide::convert::json;
+
+    #[test]
+    fn test_parse_greenfield_payload() {
+        let body = json!({
+            "deliveryId": "8pTyhG1UZQh3DNjRg4j5Sd",
+            "webhookId": "9s3b2EFbUcG5oXqrLeN3ua",
+            "originalDeliveryId": "8pTyhG1UZQh3DNjRg4j5Sd",
+            "isRedelivery": false,
+            "type": "InvoiceReceivedPayment",
+            "timestamp": 1626876016,
+            "storeId": "AbCdEf",
+            "invoiceId": "QwErTy",
+            "afterExpiration": true,
+            "paymentMethod": "BTC",
+            "payment": {
+                "id": "a1b2c3-0",
+                "receivedDate": 1626876015,
+                "value": "0.00012",
+                "fee": "0.00000101",
+                "status": "Processing",
+                "destination": "bc1qexample"
+            }
+        });
+
+        let webhook: Webhook = serde_json::from_value(body).unwrap();
+
+        assert_eq!(
+            webhook.delivery_id.as_deref(),
+            Some("8pTyhG1UZQh3DNjRg4j5Sd")
+        );
+        assert_eq!(webhook.store_id.as_deref(), Some("AbCdEf"));
+        assert_eq!(webhook.timestamp, Some(1626876016));
+        assert_eq!(webhook.event.status(), Some(InvoiceStatus::ReceivedPayment));
+        match webhook.event {
+            WebhookEvent::InvoiceReceivedPayment {
+                after_expiration,
+                payment: Some(payment),
+                ..
+            } => {
+                assert!(after_expiration);
+                assert_eq!(payment.value.as_deref(), Some("0.00012"));
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        let unknown: Webhook =
+            serde_json::from_value(json!({"type": "PayoutCreated", "invoiceId": "QwErTy"}))
+                .unwrap();
+        assert_eq!(unknown.event, WebhookEvent::Unsupported);
+    }
+}
@@ -1,90 +1,652 @@
-use super::invoice::InvoiceCommands;
+use super::invoice::{InvoiceCommands, InvoiceUpdate};
+use super::protocol::{
+    invoice_key, ClientMessage, CloseReason, ErrorCode, ServerMessage, PROTOCOL_V1,
+};
 use super::state::State;
-use async_std::task;
-use redis::Commands;
+use super::watcher::{Outbox, Since, Watcher};
+use async_std::future;
+use async_std::stream::StreamExt;
+use async_trait::async_trait;
+use futures_lite::future::or;
 use serde::Deserialize;
-use std::time::Duration;
-use tide::convert::json;
+use std::time::{Duration, Instant};
+use tide_websockets::tungstenite::protocol::frame::coding::CloseCode;
+use tide_websockets::tungstenite::protocol::CloseFrame;
+use tide_websockets::{Message, WebSocketConnection};
 
 #[derive(Deserialize)]
 struct InvoiceQuery {
-    invoice_id: String,
+    store_id: Option<String>,
+    invoice_id: Option<String>,
+    since: Option<String>,
 }
 
+/// A client connection, speaking `btcpay-ws.v1` if it asked to and the
+/// legacy frames otherwise.
+struct Socket {
+    stream: WebSocketConnection,
+    typed: bool,
+}
+
+impl Socket {
+    fn new<T: InvoiceCommands + std::clone::Clone>(
+        req: &tide::Request<State<T>>,
+        stream: WebSocketConnection,
+    ) -> Socket {
+        let typed = req
+            .header("Sec-WebSocket-Protocol")
+            .is_some_and(|protocols| {
+                protocols
+                    .as_str()
+                    .split(',')
+                    .any(|protocol| protocol.trim() == PROTOCOL_V1)
+            });
+        Socket { stream, typed }
+    }
+
+    /// Sends `closing`, then a close frame with the matching code.
+    async fn close(&self, reason: CloseReason) -> tide::Result<()> {
+        self.send(&ServerMessage::Closing { reason }).await?;
+        let frame = CloseFrame {
+            code: CloseCode::from(reason.close_code()),
+            reason: reason.as_str().into(),
+        };
+        self.stream.send(Message::Close(Some(frame))).await?;
+        Ok(())
+    }
+
+    async fn ping(&self) -> tide::Result<()> {
+        self.stream.send(Message::Ping(Vec::new())).await?;
+        Ok(())
+    }
+
+    /// Answers the peer's close frame, flushing the reply tungstenite queued
+    /// when it read it. The connection is gone either way.
+    async fn acknowledge_close(&self) {
+        if let Err(e) = self.stream.send(Message::Close(None)).await {
+            if !matches!(e, tide_websockets::Error::ConnectionClosed) {
+                log::debug!("Failed to acknowledge websocket close, {}", e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Outbox for Socket {
+    async fn send(&self, message: &ServerMessage) -> tide::Result<()> {
+        if self.typed {
+            return self.stream.send_json(message).await;
+        }
+        match message.to_legacy() {
+            Some(frame) => self.stream.send_json(&frame).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// What woke the connection loop up.
+enum Event {
+    Update(Option<Box<InvoiceUpdate>>),
+    Frame(Option<Result<Message, tide_websockets::Error>>),
+    /// A ping, the lifetime or a pending invoice is due.
+    Timer,
+}
+
+/// A websocket's watcher, plus the heartbeat only websockets have.
+struct Connection<'a, T: InvoiceCommands + std::clone::Clone> {
+    watcher: Watcher<'a, T, Socket>,
+    ping_interval: Option<Duration>,
+    next_ping: Option<Instant>,
+    /// A ping was sent and no pong has come back yet.
+    awaiting_pong: bool,
+}
+
+impl<'a, T: InvoiceCommands + std::clone::Clone> Connection<'a, T> {
+    async fn on_message(&mut self, text: &str) -> tide::Result<()> {
+        let watcher = &mut self.watcher;
+        match serde_json::from_str::<ClientMessage>(text) {
+            Ok(ClientMessage::Subscribe {
+                store_id,
+                invoice_id,
+                since,
+            }) => {
+                watcher
+                    .subscribe_since(
+                        invoice_key(store_id.as_deref(), &invoice_id),
+                        since.as_deref().map(Since::parse),
+                    )
+                    .await
+            }
+            Ok(ClientMessage::Unsubscribe {
+                store_id,
+                invoice_id,
+            }) => {
+                watcher
+                    .unsubscribe(invoice_key(store_id.as_deref(), &invoice_id))
+                    .await
+            }
+            Ok(ClientMessage::Resubscribe {
+                store_id,
+                invoice_id,
+            }) => {
+                watcher
+                    .resubscribe(invoice_key(store_id.as_deref(), &invoice_id))
+                    .await
+            }
+            Ok(ClientMessage::Ping) => watcher.outbox.send(&ServerMessage::Pong).await,
+            Err(e) => {
+                watcher
+                    .outbox
+                    .send(&ServerMessage::Error {
+                        code: ErrorCode::BadMessage,
+                        message: e.to_string(),
+                        invoice: None,
+                    })
+                    .await
+            }
+        }
+    }
+
+    /// The soonest a ping or anything the watcher waits on is due.
+    fn next_deadline(&self) -> Option<Instant> {
+        self.watcher
+            .next_deadline()
+            .into_iter()
+            .chain(self.next_ping)
+            .min()
+    }
+
+    async fn on_timer(&mut self) -> tide::Result<()> {
+        let now = Instant::now();
+        if self.next_ping.is_some_and(|at| at <= now) {
+            if self.awaiting_pong {
+                log::debug!("Dropping websocket that missed a pong");
+                self.watcher.close_reason = Some(CloseReason::HeartbeatTimeout);
+                return Ok(());
+            }
+            self.watcher.outbox.ping().await?;
+            self.awaiting_pong = true;
+            self.next_ping = self.ping_interval.map(|interval| now + interval);
+        }
+        self.watcher.on_timer().await
+    }
+}
+
+/// Streams invoice status changes. `?invoice_id=` watches that one invoice
+/// and closes once it is final, otherwise the client sends `subscribe` and
+/// `unsubscribe` for as many invoices as it likes.
 pub async fn websocket<T: InvoiceCommands + std::clone::Clone>(
     req: tide::Request<State<T>>,
-    stream: tide_websockets::WebSocketConnection,
+    stream: WebSocketConnection,
 ) -> tide::Result<()> {
     let query = req.query::<InvoiceQuery>()?;
     let state = req.state();
+    let mut frames = stream.clone();
+    let socket = Socket::new(&req, stream);
+    let mut connection = Connection {
+        watcher: Watcher::new(state, socket, query.invoice_id.is_some()),
+        ping_interval: state.ping_interval,
+        next_ping: state
+            .ping_interval
+            .map(|interval| Instant::now() + interval),
+        awaiting_pong: false,
+    };
+
+    connection
+        .watcher
+        .outbox
+        .send(&ServerMessage::Hello {
+            protocol: PROTOCOL_V1,
+        })
+        .await?;
 
-    let mut previous_string: String = {
-        let db = state.db.lock().await;
-        match db.get_invoice_status(query.invoice_id.clone()).await {
-            Ok(status) => status,
-            Err(_) => {
-                stream
-                    .send_json(&json!({
-                        "message": "status not found"
-                    }))
-                    .await?;
+    if let Some(invoice_id) = &query.invoice_id {
+        connection
+            .watcher
+            .subscribe_since(
+                invoice_key(query.store_id.as_deref(), invoice_id),
+                query.since.as_deref().map(Since::parse),
+            )
+            .await?;
+    }
+
+    while connection.watcher.close_reason.is_none() {
+        let deadline = connection.next_deadline();
+        let next = or(
+            async { Event::Update(connection.watcher.recv().await.map(Box::new)) },
+            async { Event::Frame(frames.next().await) },
+        );
+        let event = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                future::timeout(remaining, next)
+                    .await
+                    .unwrap_or(Event::Timer)
+            }
+            None => next.await,
+        };
+
+        match event {
+            Event::Update(Some(update)) => connection.watcher.on_update(*update).await?,
+            Event::Update(None) => break,
+            Event::Timer => connection.on_timer().await?,
+            Event::Frame(Some(Ok(Message::Text(text)))) => connection.on_message(&text).await?,
+            // The peer went away, returning drops the subscription
+            Event::Frame(None) | Event::Frame(Some(Err(_))) => return Ok(()),
+            Event::Frame(Some(Ok(Message::Pong(_)))) => connection.awaiting_pong = false,
+            Event::Frame(Some(Ok(Message::Close(_)))) => {
+                connection.watcher.outbox.acknowledge_close().await;
                 return Ok(());
             }
+            Event::Frame(Some(Ok(_))) => {}
         }
-    };
+    }
+
+    if let Some(reason) = connection.watcher.close_reason {
+        connection.watcher.outbox.close(reason).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::invoice::{InvoiceKey, InvoiceStatus, InvoiceTerms};
+    use crate::mock::{mock_state, publish, MockDb};
+    use crate::protocol::PROTOCOLS;
+    use crate::store::DEFAULT_STORE;
+    use async_tungstenite::async_std::{connect_async, ConnectStream};
+    use async_tungstenite::tungstenite::client::IntoClientRequest;
+    use async_tungstenite::WebSocketStream;
+    use futures::SinkExt;
+    use std::time::{Duration, SystemTime};
+    use tide::convert::json;
+    use tide::listener::Listener;
+    use tide_websockets::WebSocket;
+
+    /// Serves `/ws` on a local port, returns its base URL.
+    async fn serve(state: State<MockDb>) -> String {
+        let mut app = tide::with_state(state);
+        app.at("/ws")
+            .with(WebSocket::new(websocket).with_protocols(PROTOCOLS))
+            .get(|_| async move { Ok("not a websocket request") });
+        let mut listener = app.bind("127.0.0.1:0").await.unwrap();
+        let url = listener.info()[0].connection().replace("http://", "ws://");
+        async_std::task::spawn(async move { listener.accept().await });
+        url
+    }
+
+    async fn next_json(socket: &mut WebSocketStream<ConnectStream>) -> Option<serde_json::Value> {
+        while let Some(message) = socket.next().await {
+            if let Message::Text(text) = message.unwrap() {
+                return Some(serde_json::from_str(&text).unwrap());
+            }
+        }
+        None
+    }
+
+    /// Connects speaking `btcpay-ws.v1`.
+    async fn connect_v1(url: String) -> WebSocketStream<ConnectStream> {
+        let mut request = url.into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("Sec-WebSocket-Protocol", PROTOCOL_V1.parse().unwrap());
+        let (socket, response) = connect_async(request).await.unwrap();
+        assert_eq!(
+            response.headers().get("Sec-WebSocket-Protocol").unwrap(),
+            PROTOCOL_V1
+        );
+        socket
+    }
+
+    #[actix_rt::test]
+    async fn test_websocket_v1() {
+        let state = mock_state();
+        let url = serve(state.clone()).await;
+        let mut socket = connect_v1(format!("{}/ws?invoice_id=bob", url)).await;
+
+        assert_eq!(
+            next_json(&mut socket).await,
+            Some(json!({"type": "hello", "protocol": "btcpay-ws.v1"}))
+        );
+        assert_eq!(
+            next_json(&mut socket).await,
+            Some(json!({"type": "pending", "storeId": "default", "invoiceId": "bob"}))
+        );
+
+        socket
+            .send(Message::Text(json!({"type": "ping"}).to_string()))
+            .await
+            .unwrap();
+        assert_eq!(next_json(&mut socket).await, Some(json!({"type": "pong"})));
+
+        socket
+            .send(Message::Text(json!({"type": "dance"}).to_string()))
+            .await
+            .unwrap();
+        let error = next_json(&mut socket).await.unwrap();
+        assert_eq!(error["type"], "error");
+        assert_eq!(error["code"], "bad_message");
+
+        let cursor = publish(&state, "bob", InvoiceStatus::Created).await;
+        assert_eq!(
+            next_json(&mut socket).await,
+            Some(json!({
+                "type": "status",
+                "storeId": "default",
+                "invoiceId": "bob",
+                "status": "InvoiceCreated",
+                "cursor": cursor
+            }))
+        );
+
+        publish(&state, "bob", InvoiceStatus::Settled).await;
+        assert_eq!(
+            next_json(&mut socket).await.unwrap()["status"],
+            "InvoiceSettled"
+        );
+        assert_eq!(
+            next_json(&mut socket).await,
+            Some(json!({"type": "closing", "reason": "invoice_final"}))
+        );
+        assert_eq!(
+            close_frame(&mut socket).await.unwrap().code,
+            CloseCode::Normal
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_websocket_multiplex() {
+        let state = mock_state();
+        let cursor = publish(&state, "carol", InvoiceStatus::Processing).await;
+        let url = serve(state.clone()).await;
+        let mut socket = connect_v1(format!("{}/ws", url)).await;
+        assert_eq!(next_json(&mut socket).await.unwrap()["type"], "hello");
+
+        for invoice_id in ["bob", "carol"] {
+            socket
+                .send(Message::Text(
+                    json!({"type": "subscribe", "invoiceId": invoice_id}).to_string(),
+                ))
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            next_json(&mut socket).await,
+            Some(json!({"type": "pending", "storeId": "default", "invoiceId": "bob"}))
+        );
+        assert_eq!(
+            next_json(&mut socket).await,
+            Some(json!({
+                "type": "status",
+                "storeId": "default",
+                "invoiceId": "carol",
+                "status": "InvoiceProcessing",
+                "cursor": cursor
+            }))
+        );
+
+        publish(&state, "bob", InvoiceStatus::Created).await;
+        let update = next_json(&mut socket).await.unwrap();
+        assert_eq!(update["invoiceId"], "bob");
+        assert_eq!(update["status"], "InvoiceCreated");
+
+        socket
+            .send(Message::Text(
+                json!({"type": "unsubscribe", "invoiceId": "carol"}).to_string(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_json(&mut socket).await,
+            Some(json!({"type": "unsubscribed", "storeId": "default", "invoiceId": "carol"}))
+        );
+
+        // Neither an unsubscribed invoice nor a final one ends the connection
+        publish(&state, "carol", InvoiceStatus::Settled).await;
+        publish(&state, "bob", InvoiceStatus::Settled).await;
+        let update = next_json(&mut socket).await.unwrap();
+        assert_eq!(update["invoiceId"], "bob");
+        assert_eq!(update["status"], "InvoiceSettled");
+
+        socket
+            .send(Message::Text(json!({"type": "ping"}).to_string()))
+            .await
+            .unwrap();
+        assert_eq!(next_json(&mut socket).await, Some(json!({"type": "pong"})));
+    }
+
+    #[actix_rt::test]
+    async fn test_websocket_resubscribe_and_close() {
+        let state = mock_state();
+        let url = serve(state.clone()).await;
+        let mut socket = connect_v1(format!("{}/ws?invoice_id=bob", url)).await;
+        assert_eq!(next_json(&mut socket).await.unwrap()["type"], "hello");
+        assert_eq!(next_json(&mut socket).await.unwrap()["type"], "pending");
+
+        publish(&state, "bob", InvoiceStatus::Created).await;
+        assert_eq!(
+            next_json(&mut socket).await.unwrap()["status"],
+            "InvoiceCreated"
+        );
+
+        // The unchanged status is sent again
+        socket
+            .send(Message::Text(
+                json!({"type": "resubscribe", "invoiceId": "bob"}).to_string(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_json(&mut socket).await.unwrap()["status"],
+            "InvoiceCreated"
+        );
+
+        socket.close(None).await.unwrap();
+        assert!(matches!(socket.next().await, Some(Ok(Message::Close(_)))));
+
+        let bob = InvoiceKey::new(DEFAULT_STORE, "bob");
+        for _ in 0..100 {
+            if !state.hub.is_watched(&bob) {
+                return;
+            }
+            async_std::task::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("subscription outlived the connection");
+    }
+
+    /// Reads up to the close frame, skipping everything else.
+    async fn close_frame(
+        socket: &mut WebSocketStream<ConnectStream>,
+    ) -> Option<CloseFrame<'static>> {
+        while let Some(message) = socket.next().await {
+            if let Message::Close(frame) = message.unwrap() {
+                return frame;
+            }
+        }
+        None
+    }
+
+    #[actix_rt::test]
+    async fn test_websocket_heartbeat() {
+        let state = State {
+            ping_interval: Some(Duration::from_millis(30)),
+            ..mock_state()
+        };
+        let url = serve(state.clone()).await;
+        let mut socket = connect_v1(format!("{}/ws?invoice_id=bob", url)).await;
+        assert_eq!(next_json(&mut socket).await.unwrap()["type"], "hello");
+        assert_eq!(next_json(&mut socket).await.unwrap()["type"], "pending");
+
+        // Reading answers the pings, so the connection outlives several
+        assert!(
+            future::timeout(Duration::from_millis(200), next_json(&mut socket))
+                .await
+                .is_err()
+        );
+        publish(&state, "bob", InvoiceStatus::Created).await;
+        assert_eq!(
+            next_json(&mut socket).await.unwrap()["status"],
+            "InvoiceCreated"
+        );
+
+        // Not reading, pings go unanswered
+        async_std::task::sleep(Duration::from_millis(200)).await;
+        assert_eq!(
+            next_json(&mut socket).await,
+            Some(json!({"type": "closing", "reason": "heartbeat_timeout"}))
+        );
+        let frame = close_frame(&mut socket).await.unwrap();
+        assert_eq!(frame.code, CloseCode::Away);
+        assert_eq!(frame.reason, "heartbeat_timeout");
+    }
+
+    #[actix_rt::test]
+    async fn test_websocket_max_lifetime() {
+        let url = serve(State {
+            max_socket_lifetime: Some(Duration::from_millis(50)),
+            ..mock_state()
+        })
+        .await;
+        let mut socket = connect_v1(format!("{}/ws", url)).await;
+        assert_eq!(next_json(&mut socket).await.unwrap()["type"], "hello");
+        assert_eq!(
+            next_json(&mut socket).await,
+            Some(json!({"type": "closing", "reason": "max_lifetime"}))
+        );
+        assert_eq!(
+            close_frame(&mut socket).await.unwrap().code,
+            CloseCode::Away
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_websocket_monitoring_ends() {
+        // Recorded by the reconciler, no BTCPay needed to watch
+        let state = mock_state();
+        publish(&state, "bob", InvoiceStatus::Created).await;
+        state
+            .db
+            .set_invoice_terms(
+                &InvoiceKey::new(DEFAULT_STORE, "bob"),
+                &InvoiceTerms {
+                    monitoring_ends: Some(SystemTime::now() + Duration::from_millis(500)),
+                    ..InvoiceTerms::default()
+                },
+            )
+            .await
+            .unwrap();
+        let url = serve(state).await;
 
-    loop {
-        task::sleep(Duration::from_secs(1)).await;
-
-        {
-            let db = state.db.lock().await;
-            match db.get_invoice_status(query.invoice_id.clone()).await {
-                Ok(status) => {
-                    if status == previous_string.clone() {
-                        continue;
-                    }
-
-                    previous_string = status.clone();
-
-                    log::trace!("sending status");
-                    stream
-                        .send_json(&json!({
-                            "message": { "invoiceStatus": status[..] }
-                        }))
-                        .await?;
-
-                    match &status[..] {
-                        "InvoiceExpired" | "InvoicePayed" => {
-                            break;
-                        }
-                        "InvoiceRecievedPayment" | "InvoiceCreated" => {}
-                        _ => {
-                            log::error!(
-                                "Non supported status {} on invoice {}",
-                                &status[..],
-                                query.invoice_id.clone()
-                            );
-                            stream
-                                .send_json(&json!({
-                                    "message": "An error occured"
-                                }))
-                                .await?;
-                            return Ok(());
-                        }
-                    };
-                }
-                Err(e) => {
-                    log::error!("Error connecting to redis '{:?}'", e);
-                    stream
-                        .send_json(&json!({
-                            "message": "An error occured"
-                        }))
-                        .await?;
-                    return Ok(());
-                }
-            };
+        // Closed once BTCPay stops monitoring, well before the lifetime, then
+        // straight away for a client reconnecting after
+        for _ in 0..2 {
+            let mut socket = connect_v1(format!("{}/ws?invoice_id=bob", url)).await;
+            assert_eq!(next_json(&mut socket).await.unwrap()["type"], "hello");
+            assert_eq!(
+                next_json(&mut socket).await.unwrap()["status"],
+                "InvoiceCreated"
+            );
+            assert_eq!(
+                next_json(&mut socket).await,
+                Some(json!({"type": "unsubscribed", "storeId": "default", "invoiceId": "bob"}))
+            );
+            assert_eq!(
+                next_json(&mut socket).await,
+                Some(json!({"type": "closing", "reason": "monitoring_ended"}))
+            );
+            // Not going away, there is nothing to reconnect for
+            assert_eq!(
+                close_frame(&mut socket).await.unwrap().code,
+                CloseCode::Normal
+            );
         }
     }
 
-    return Ok(());
+    #[actix_rt::test]
+    async fn test_websocket_replay() {
+        let state = mock_state();
+        let created = publish(&state, "bob", InvoiceStatus::Created).await;
+        let received = publish(&state, "bob", InvoiceStatus::ReceivedPayment).await;
+        let processing = publish(&state, "bob", InvoiceStatus::Processing).await;
+        let url = serve(state.clone()).await;
+
+        // Up to date, only live updates follow
+        let mut socket =
+            connect_v1(format!("{}/ws?invoice_id=bob&since={}", url, processing)).await;
+        assert_eq!(next_json(&mut socket).await.unwrap()["type"], "hello");
+        socket
+            .send(Message::Text(json!({"type": "ping"}).to_string()))
+            .await
+            .unwrap();
+        assert_eq!(next_json(&mut socket).await, Some(json!({"type": "pong"})));
+
+        let mut socket = connect_v1(format!("{}/ws?invoice_id=bob&since={}", url, created)).await;
+        assert_eq!(next_json(&mut socket).await.unwrap()["type"], "hello");
+        for (status, cursor) in [
+            ("InvoiceReceivedPayment", &received),
+            ("InvoiceProcessing", &processing),
+        ] {
+            assert_eq!(
+                next_json(&mut socket).await,
+                Some(json!({
+                    "type": "status",
+                    "storeId": "default",
+                    "invoiceId": "bob",
+                    "status": status,
+                    "cursor": cursor
+                }))
+            );
+        }
+
+        let settled = publish(&state, "bob", InvoiceStatus::Settled).await;
+        assert_eq!(next_json(&mut socket).await.unwrap()["cursor"], settled);
+        assert_eq!(
+            next_json(&mut socket).await,
+            Some(json!({"type": "closing", "reason": "invoice_final"}))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_websocket_pending() {
+        let state = mock_state();
+        let url = serve(state.clone()).await;
+        let (mut socket, _) = connect_async(format!("{}/ws?invoice_id=bob", url))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            next_json(&mut socket).await,
+            Some(json!({"message": "pending"}))
+        );
+
+        // InvoiceCreated arriving after the page opened
+        publish(&state, "bob", InvoiceStatus::Created).await;
+
+        assert_eq!(
+            next_json(&mut socket).await,
+            Some(json!({"message": {"invoiceStatus": "InvoiceCreated", "event": null}}))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_websocket_pending_timeout() {
+        let url = serve(State {
+            pending_timeout: Duration::from_millis(50),
+            ..mock_state()
+        })
+        .await;
+        let (mut socket, _) = connect_async(format!("{}/ws?invoice_id=bob", url))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            next_json(&mut socket).await,
+            Some(json!({"message": "pending"}))
+        );
+        assert_eq!(
+            next_json(&mut socket).await,
+            Some(json!({"message": "status not found"}))
+        );
+    }
 }